@@ -1,6 +1,18 @@
 use std::path::Path;
 
-/// Put the linker script somewhere the linker can find it.
+#[path = "src/songformat.rs"]
+mod songformat;
+
+#[path = "src/tracker.rs"]
+mod tracker;
+
+#[path = "src/levelformat.rs"]
+mod levelformat;
+
+#[path = "src/levelfile.rs"]
+mod levelfile;
+
+/// Put the linker script somewhere the linker can find it, and convert music and levels.
 fn main() {
     let dest_path = Path::new("external/openfpga-litex/lang/linker");
 
@@ -26,5 +38,38 @@ fn main() {
         }
     }
 
+    {
+        // Convert tracker text files into the binary songs music.rs embeds.
+        let out_dir = std::env::var("OUT_DIR").unwrap();
+        println!("cargo:rerun-if-changed=src/tracker.rs");
+        println!("cargo:rerun-if-changed=src/songformat.rs");
+        for name in ["title", "game", "victory"] {
+            let src = format!("music/{name}.txt");
+            println!("cargo:rerun-if-changed={src}");
+            let text = std::fs::read_to_string(&src).unwrap_or_else(|e| panic!("{src}: {e}"));
+            let song = tracker::convert(&text).unwrap_or_else(|e| panic!("{src}: {e}"));
+            std::fs::write(Path::new(&out_dir).join(format!("{name}.mbt")), song).unwrap();
+        }
+    }
+
+    {
+        // Convert level text files into the single binary file level.rs embeds. Levels play in filename order.
+        use litex_pac::constants::{MAX_DISPLAY_WIDTH, MAX_DISPLAY_HEIGHT};
+        let out_dir = std::env::var("OUT_DIR").unwrap();
+        println!("cargo:rerun-if-changed=src/levelfile.rs");
+        println!("cargo:rerun-if-changed=src/levelformat.rs");
+        println!("cargo:rerun-if-changed=levels");
+        let mut paths:Vec<_> = std::fs::read_dir("levels").unwrap().map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "txt")).collect();
+        paths.sort();
+        let levels:Vec<_> = paths.iter().map(|path| {
+            println!("cargo:rerun-if-changed={}", path.display());
+            let text = std::fs::read_to_string(path).unwrap_or_else(|e| panic!("{}: {e}", path.display()));
+            levelfile::convert(&text, MAX_DISPLAY_WIDTH, MAX_DISPLAY_HEIGHT).unwrap_or_else(|e| panic!("{}: {e}", path.display()))
+        }).collect();
+        let packed = levelfile::pack(&levels).unwrap_or_else(|e| panic!("levels: {e}"));
+        std::fs::write(Path::new(&out_dir).join("levels.mbl"), packed).unwrap();
+    }
+
     // GOT SLINT? PUT IT HERE
 }
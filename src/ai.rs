@@ -0,0 +1,114 @@
+// Computer player. It drives a paddle through a Controller it "presses" itself, so game code reads
+// it the same way as a person on a controller port. Used for the title screen demo and the versus CPU.
+// Notes:
+//    The AI predicts where the nearest incoming ball will cross its paddle's line, counting bounces
+//    off the side walls but not off vaders. It looks again every so often, which catches those.
+//    Difficulty is how often it looks (its reaction time) and how far off its guesses land.
+
+use glam::IVec2;
+use crate::fixed::FVec2;
+use crate::input::{Action, Controller};
+use crate::irect2::IRect2;
+use crate::rng::Rng;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Difficulty {
+    pub reaction: i32, // Logic steps between looks at the ball
+    pub error: i32,    // Guesses land up to this many pixels either side
+}
+
+impl Difficulty {
+    pub const EASY:Difficulty = Difficulty { reaction:30, error:28 };
+    pub const NORMAL:Difficulty = Difficulty { reaction:15, error:14 };
+    pub const HARD:Difficulty = Difficulty { reaction:4, error:4 };
+}
+
+const SERVE_WAIT:i32 = 45; // Logic steps to hold a ball before launching it
+const DEADBAND:i32 = 3; // Don't bother moving when the paddle center is this close to the target
+
+pub struct Ai {
+    pub controller: Controller, // What the AI is pressing; read it like a real controller
+    difficulty: Difficulty,
+    rng: Rng,
+    target: Option<i32>, // x to put the paddle center at, None to wait in the middle
+    look: i32,           // Logic steps until it looks at the ball again
+    holding: i32,        // Logic steps it's been holding a ball
+}
+
+// Where a ball center at pos, moving at velocity, will cross the line at y, bouncing between walls at
+// left and right (the limits of the ball's center). None if it's not heading for the line.
+pub fn predict_x(pos:IVec2, velocity:FVec2, y:i32, left:i32, right:i32) -> Option<i32> {
+    let dy = y - pos.y;
+    if velocity.y.0 == 0 || dy.signum() != velocity.y.signum() { return None }
+    let x = pos.x + velocity.x.0 * dy / velocity.y.0; // As if there were no walls
+    let width = right - left;
+    if width <= 0 { return Some(left) }
+    let folded = (x - left).rem_euclid(width*2); // Each wall bounce mirrors the path
+    Some(left + if folded > width { width*2 - folded } else { folded })
+}
+
+impl Ai {
+    pub fn new(difficulty:Difficulty, seed:u32) -> Self {
+        Ai { controller:Default::default(), difficulty, rng:Rng::new(seed), target:None, look:0, holding:0 }
+    }
+
+    // Decide what to press this logic step.
+    // paddle is the AI's paddle, face is -1 if it sends balls up or 1 if down. balls are the center and
+    // velocity of each ball in flight, holding is true if a ball is sitting on the paddle, and bounds
+    // is where ball centers can go.
+    pub fn update(&mut self, paddle:IRect2, face:i32, balls:impl Iterator<Item=(IVec2, FVec2)>, holding:bool, bounds:IRect2) {
+        let line = if face < 0 { paddle.ul.y } else { paddle.br.y };
+        self.look -= 1;
+        if self.look <= 0 {
+            self.look = self.difficulty.reaction;
+            // Watch whichever incoming ball is closest
+            let incoming = balls.filter(|(_, velocity)| velocity.y.signum() == -face)
+                .min_by_key(|(pos, _)| (line - pos.y).abs());
+            let error = self.difficulty.error;
+            self.target = incoming.and_then(|(pos, velocity)| predict_x(pos, velocity, line, bounds.ul.x, bounds.br.x))
+                .map(|x| x + self.rng.below(error as u32*2 + 1) as i32 - error);
+        }
+
+        let offset = self.target.unwrap_or(bounds.center().x) - paddle.center().x;
+        let mut actions = 0;
+        if offset < -DEADBAND { actions |= 1 << Action::MoveLeft as u8 }
+        if offset > DEADBAND { actions |= 1 << Action::MoveRight as u8 }
+        self.holding = if holding { self.holding + 1 } else { 0 };
+        if self.holding > SERVE_WAIT { actions |= 1 << Action::Launch as u8 }
+        self.controller.set_actions(actions);
+    }
+}
+
+// Unit tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixed::Fixed;
+
+    #[test]
+    fn predict() {
+        let v = |x:i32, y:i32| FVec2::from_ivec2(IVec2::new(x, y));
+        assert_eq!(predict_x(IVec2::new(50, 100), v(1, 1), 150, 0, 200), Some(100), "Straight shot");
+        assert_eq!(predict_x(IVec2::new(50, 100), v(1, -1), 150, 0, 200), None, "Heading away");
+        assert_eq!(predict_x(IVec2::new(150, 100), v(1, 1), 200, 0, 200), Some(150), "One bounce off the right wall");
+        assert_eq!(predict_x(IVec2::new(50, 200), v(-3, -1), 100, 0, 200), Some(150), "Two bounces, heading up");
+    }
+
+    #[test]
+    fn plays() {
+        let bounds = IRect2::new(IVec2::ZERO, IVec2::new(200, 200));
+        let paddle = IRect2::new(IVec2::new(10, 190), IVec2::new(50, 198));
+        let ball = (IVec2::new(150, 100), FVec2::new(Fixed::ZERO, Fixed::from_int(2)));
+        let mut ai = Ai::new(Difficulty::HARD, 1);
+        ai.update(paddle, -1, [ball].into_iter(), false, bounds);
+        assert!(ai.controller.held(Action::MoveRight) && !ai.controller.held(Action::MoveLeft), "Should chase the ball");
+
+        let mut launched = 0;
+        for _ in 0..SERVE_WAIT*2 {
+            ai.update(paddle, -1, core::iter::empty(), true, bounds);
+            if ai.controller.triggered(Action::Launch) { launched += 1 }
+        }
+        assert_eq!(launched, 1, "Should launch a held ball once");
+    }
+}
@@ -0,0 +1,187 @@
+// Fixed point number and 2D vector classes, for sub-pixel motion without the FPU
+// Notes:
+//    Fixed is Q24.8: an i32 counting 1/256ths of a pixel.
+//    Converting to pixels rounds to nearest (halves round up, toward +infinity), so an object
+//    moving steadily in either direction changes pixel at evenly spaced points.
+//    Multiplying two Fixeds goes through i64 so it can't overflow midway.
+//    length() and with_length() use 64-bit division, which is slow on RV32; they're for events like
+//    bounces, not inner loops.
+//    Angles are whole degrees, from a sine table; 0 degrees points along +y (down the screen),
+//    positive angles turn toward +x.
+
+use core::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+use glam::IVec2;
+
+pub const FRAC_BITS:u32 = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Fixed(pub i32);
+
+impl Fixed {
+    pub const ZERO:Fixed = Fixed(0);
+    pub const ONE:Fixed = Fixed(1<<FRAC_BITS);
+    pub const HALF:Fixed = Fixed(1<<(FRAC_BITS-1));
+
+    pub const fn from_int(i:i32) -> Self { Fixed(i << FRAC_BITS) }
+
+    // num/den, rounded toward zero
+    pub const fn from_ratio(num:i32, den:i32) -> Self { Fixed((num << FRAC_BITS) / den) }
+
+    // Nearest whole number
+    pub fn round(self) -> i32 { (self.0 + Self::HALF.0) >> FRAC_BITS }
+
+    // Smallest whole number not less than this
+    pub fn ceil(self) -> i32 { (self.0 + Self::ONE.0 - 1) >> FRAC_BITS }
+
+    pub fn abs(self) -> Self { Fixed(self.0.abs()) }
+    pub fn signum(self) -> i32 { self.0.signum() }
+    pub fn min(self, other:Fixed) -> Self { Fixed(self.0.min(other.0)) }
+    pub fn max(self, other:Fixed) -> Self { Fixed(self.0.max(other.0)) }
+
+    // Same magnitude, sign of other (positive if other is zero)
+    pub fn with_sign_of(self, other:Fixed) -> Self { if other.0 < 0 { -self.abs() } else { self.abs() } }
+}
+
+impl Add for Fixed { type Output = Fixed; fn add(self, o:Fixed) -> Fixed { Fixed(self.0 + o.0) } }
+impl Sub for Fixed { type Output = Fixed; fn sub(self, o:Fixed) -> Fixed { Fixed(self.0 - o.0) } }
+impl Neg for Fixed { type Output = Fixed; fn neg(self) -> Fixed { Fixed(-self.0) } }
+impl AddAssign for Fixed { fn add_assign(&mut self, o:Fixed) { self.0 += o.0 } }
+impl SubAssign for Fixed { fn sub_assign(&mut self, o:Fixed) { self.0 -= o.0 } }
+impl Mul for Fixed { type Output = Fixed; fn mul(self, o:Fixed) -> Fixed { Fixed(((self.0 as i64 * o.0 as i64) >> FRAC_BITS) as i32) } }
+impl Mul<i32> for Fixed { type Output = Fixed; fn mul(self, o:i32) -> Fixed { Fixed(self.0 * o) } }
+impl Div<i32> for Fixed { type Output = Fixed; fn div(self, o:i32) -> Fixed { Fixed(self.0 / o) } }
+
+// Trig
+
+// sin of 0..=90 degrees, 16 fractional bits
+const SIN_TABLE:[i32; 91] = [
+    0, 1144, 2287, 3430, 4572, 5712, 6850, 7987, 9121, 10252,
+    11380, 12505, 13626, 14742, 15855, 16962, 18064, 19161, 20252, 21336,
+    22415, 23486, 24550, 25607, 26656, 27697, 28729, 29753, 30767, 31772,
+    32768, 33754, 34729, 35693, 36647, 37590, 38521, 39441, 40348, 41243,
+    42126, 42995, 43852, 44695, 45525, 46341, 47143, 47930, 48703, 49461,
+    50203, 50931, 51643, 52339, 53020, 53684, 54332, 54963, 55578, 56175,
+    56756, 57319, 57865, 58393, 58903, 59396, 59870, 60326, 60764, 61183,
+    61584, 61966, 62328, 62672, 62997, 63303, 63589, 63856, 64104, 64332,
+    64540, 64729, 64898, 65048, 65177, 65287, 65376, 65446, 65496, 65526,
+    65536];
+
+// sin of any whole number of degrees, 16 fractional bits
+fn sin16(deg:i32) -> i32 {
+    let deg = deg.rem_euclid(360);
+    match deg {
+        0..=90 => SIN_TABLE[deg as usize],
+        91..=180 => SIN_TABLE[(180 - deg) as usize],
+        181..=270 => -SIN_TABLE[(deg - 180) as usize],
+        _ => -SIN_TABLE[(360 - deg) as usize],
+    }
+}
+
+fn cos16(deg:i32) -> i32 { sin16(deg + 90) }
+
+// Vector class
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FVec2 {
+    pub x: Fixed,
+    pub y: Fixed,
+}
+
+impl FVec2 {
+    pub const fn new(x:Fixed, y:Fixed) -> Self { Self { x, y } }
+
+    pub const fn from_ivec2(v:IVec2) -> Self { Self { x:Fixed::from_int(v.x), y:Fixed::from_int(v.y) } }
+
+    // Nearest pixel
+    pub fn round(self) -> IVec2 { IVec2::new(self.x.round(), self.y.round()) }
+
+    // Just the component on axis 0 (x) or 1 (y), other component zero
+    pub fn only_axis(self, aid:usize) -> Self {
+        if aid == 0 { Self::new(self.x, Fixed::ZERO) } else { Self::new(Fixed::ZERO, self.y) }
+    }
+
+    // Negate one axis
+    pub fn reflect_axis(self, aid:usize) -> Self {
+        if aid == 0 { Self::new(-self.x, self.y) } else { Self::new(self.x, -self.y) }
+    }
+
+    pub fn length(self) -> Fixed {
+        let sq = self.x.0 as i64 * self.x.0 as i64 + self.y.0 as i64 * self.y.0 as i64; // Q48.16
+        Fixed(isqrt(sq as u64) as i32)
+    }
+
+    // Vector of given length pointing at angle (see notes at top)
+    pub fn from_angle(deg:i32, length:Fixed) -> Self {
+        let scale = |trig:i32| Fixed(((length.0 as i64 * trig as i64) >> 16) as i32);
+        Self::new(scale(sin16(deg)), scale(cos16(deg)))
+    }
+
+    // Same direction, given length. Zero stays zero.
+    pub fn with_length(self, length:Fixed) -> Self {
+        let current = self.length();
+        if current.0 == 0 { return self }
+        Self::new(Fixed((self.x.0 as i64 * length.0 as i64 / current.0 as i64) as i32),
+                  Fixed((self.y.0 as i64 * length.0 as i64 / current.0 as i64) as i32))
+    }
+}
+
+impl Add for FVec2 { type Output = FVec2; fn add(self, o:FVec2) -> FVec2 { FVec2::new(self.x + o.x, self.y + o.y) } }
+impl Sub for FVec2 { type Output = FVec2; fn sub(self, o:FVec2) -> FVec2 { FVec2::new(self.x - o.x, self.y - o.y) } }
+impl Neg for FVec2 { type Output = FVec2; fn neg(self) -> FVec2 { FVec2::new(-self.x, -self.y) } }
+impl AddAssign for FVec2 { fn add_assign(&mut self, o:FVec2) { *self = *self + o } }
+impl SubAssign for FVec2 { fn sub_assign(&mut self, o:FVec2) { *self = *self - o } }
+impl Mul<Fixed> for FVec2 { type Output = FVec2; fn mul(self, o:Fixed) -> FVec2 { FVec2::new(self.x * o, self.y * o) } }
+impl Mul<i32> for FVec2 { type Output = FVec2; fn mul(self, o:i32) -> FVec2 { FVec2::new(self.x * o, self.y * o) } }
+impl Div<i32> for FVec2 { type Output = FVec2; fn div(self, o:i32) -> FVec2 { FVec2::new(self.x / o, self.y / o) } }
+
+// Integer square root, rounded down
+fn isqrt(n:u64) -> u64 {
+    if n < 2 { return n }
+    let mut x = 1u64 << ((64 - n.leading_zeros()).div_ceil(2)); // Power of 2 at least sqrt(n)
+    loop { // Newton's method, from above
+        let next = (x + n / x) / 2;
+        if next >= x { return x }
+        x = next;
+    }
+}
+
+// Unit tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_math() {
+        assert_eq!(Fixed::from_ratio(3, 2).round(), 2, "Halves round up");
+        assert_eq!(Fixed::from_ratio(-3, 2).round(), -1, "Halves round up, also when negative");
+        assert_eq!(Fixed::from_ratio(1, 4).ceil(), 1);
+        assert_eq!(Fixed::from_int(3) * Fixed::from_ratio(1, 2), Fixed::from_ratio(3, 2));
+
+        let v = FVec2::from_ivec2(IVec2::new(3, 4));
+        assert_eq!(v.length(), Fixed::from_int(5));
+        assert_eq!(v.with_length(Fixed::from_int(10)).round(), IVec2::new(6, 8));
+        assert_eq!(v.reflect_axis(1).round(), IVec2::new(3, -4));
+
+        // A slow object should land on every pixel along the way, evenly
+        let mut pos = FVec2::default();
+        let step = FVec2::new(Fixed::from_ratio(1, 3), Fixed::ZERO);
+        let pixels:alloc::vec::Vec<i32> = (0..9).map(|_| { pos += step; pos.round().x }).collect();
+        assert_eq!(pixels, [0, 1, 1, 1, 2, 2, 2, 3, 3]);
+        assert_eq!(isqrt(u32::MAX as u64 * u32::MAX as u64), u32::MAX as u64);
+    }
+
+    #[test]
+    fn angles() {
+        let length = Fixed::from_int(100);
+        assert_eq!(FVec2::from_angle(0, length).round(), IVec2::new(0, 100), "0 degrees is down");
+        assert_eq!(FVec2::from_angle(90, length).round(), IVec2::new(100, 0));
+        assert_eq!(FVec2::from_angle(-90, length).round(), IVec2::new(-100, 0));
+        assert_eq!(FVec2::from_angle(180, length).round(), IVec2::new(0, -100));
+        assert_eq!(FVec2::from_angle(210, length).round(), IVec2::new(-50, -87));
+        for deg in -360..360 {
+            let len = FVec2::from_angle(deg, length).length();
+            assert!((len - length).abs() <= Fixed(3), "Angle {} should keep length: {:?}", deg, len);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+// Tiny 3x5 bitmap font, uppercase letters/digits/a little punctuation.
+// Notes:
+//    Like everything else, text is drawn with XOR, so drawing the same string twice erases it.
+//    Each glyph is 15 bits, top row in the high bits: 0bAAA_BBB_CCC_DDD_EEE
+//    Lowercase is drawn as uppercase. Unknown characters draw as '?'.
+
+use glam::IVec2;
+use crate::irect2::IRect2;
+
+pub const GLYPH_SIZE:IVec2 = IVec2::new(3, 5);
+pub const GLYPH_ADVANCE:IVec2 = IVec2::new(4, 7); // Glyph plus spacing, horizontal and line-to-line
+
+fn glyph(c:char) -> u16 {
+    match c.to_ascii_uppercase() {
+        'A' => 0b010_101_111_101_101,
+        'B' => 0b110_101_110_101_110,
+        'C' => 0b011_100_100_100_011,
+        'D' => 0b110_101_101_101_110,
+        'E' => 0b111_100_110_100_111,
+        'F' => 0b111_100_110_100_100,
+        'G' => 0b011_100_101_101_011,
+        'H' => 0b101_101_111_101_101,
+        'I' => 0b111_010_010_010_111,
+        'J' => 0b001_001_001_101_010,
+        'K' => 0b101_101_110_101_101,
+        'L' => 0b100_100_100_100_111,
+        'M' => 0b101_111_111_101_101,
+        'N' => 0b110_101_101_101_101,
+        'O' => 0b010_101_101_101_010,
+        'P' => 0b110_101_110_100_100,
+        'Q' => 0b010_101_101_110_011,
+        'R' => 0b110_101_110_101_101,
+        'S' => 0b011_100_010_001_110,
+        'T' => 0b111_010_010_010_010,
+        'U' => 0b101_101_101_101_111,
+        'V' => 0b101_101_101_101_010,
+        'W' => 0b101_101_111_111_101,
+        'X' => 0b101_101_010_101_101,
+        'Y' => 0b101_101_010_010_010,
+        'Z' => 0b111_001_010_100_111,
+        '0' => 0b111_101_101_101_111,
+        '1' => 0b010_110_010_010_111,
+        '2' => 0b110_001_010_100_111,
+        '3' => 0b110_001_010_001_110,
+        '4' => 0b101_101_111_001_001,
+        '5' => 0b111_100_110_001_110,
+        '6' => 0b011_100_111_101_111,
+        '7' => 0b111_001_010_010_010,
+        '8' => 0b111_101_111_101_111,
+        '9' => 0b111_101_111_001_110,
+        ' ' => 0,
+        ':' => 0b000_010_000_010_000,
+        '-' => 0b000_000_111_000_000,
+        '+' => 0b000_010_111_010_000,
+        '>' => 0b100_010_001_010_100,
+        '<' => 0b001_010_100_010_001,
+        '!' => 0b010_010_010_000_010,
+        '.' => 0b000_000_000_000_010,
+        '/' => 0b001_001_010_100_100,
+        _   => 0b110_001_010_000_010, // '?'
+    }
+}
+
+// Size in pixels of a single line of text
+pub fn text_size(s:&str, scale:i32) -> IVec2 {
+    let len = s.chars().count() as i32;
+    if len == 0 { return IVec2::ZERO }
+    IVec2::new(len*GLYPH_ADVANCE.x - (GLYPH_ADVANCE.x-GLYPH_SIZE.x), GLYPH_SIZE.y) * scale
+}
+
+// XOR a single line of text with its upper left at "at". Each font pixel is scale x scale screen pixels.
+pub fn text(fb: *mut u16, at:IVec2, scale:i32, color:u16, s:&str) {
+    let mut ul = at;
+    for c in s.chars() {
+        let bits = glyph(c);
+        for y in 0..GLYPH_SIZE.y {
+            for x in 0..GLYPH_SIZE.x {
+                let bit = (GLYPH_SIZE.y-1-y)*GLYPH_SIZE.x + (GLYPH_SIZE.x-1-x);
+                if bits & (1<<bit) != 0 {
+                    let pul = ul + IVec2::new(x, y)*scale;
+                    crate::fill(fb, IRect2::new(pul, pul + IVec2::splat(scale)), color);
+                }
+            }
+        }
+        ul.x += GLYPH_ADVANCE.x*scale;
+    }
+}
+
+// XOR a single line of text horizontally centered on the given rectangle, with top edge at y
+pub fn text_centered(fb: *mut u16, within:IRect2, y:i32, scale:i32, color:u16, s:&str) {
+    let size = text_size(s, scale);
+    text(fb, IVec2::new(within.center().x - size.x/2, y), scale, color, s);
+}
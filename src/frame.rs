@@ -0,0 +1,145 @@
+// Frame sync: sleep until the next vblank instead of spinning on the video registers.
+// Notes:
+//    APF_VIDEO has no interrupt of its own, so the "vblank interrupt" is TIMER0, armed to fire
+//    shortly before the next vblank is due (going by the measured frame length, see timing.rs).
+//    The CPU sleeps in wfi until then and polls for the last WAKE_EARLY cycles, so a timer that fires
+//    a little late still makes the frame. TIMER0 is the only interrupt unmasked, so nothing else can
+//    end the wfi; init() checks the interrupt really arrives, and if it doesn't (wrong irq number,
+//    different gateware) frame sync never sleeps and just polls. That only costs power, never a frame.
+//    VexRiscv under LiteX has its own interrupt mask CSR (0xBC0) on top of mie.MEIE.
+//    Deadline accounting (dropped frames, drawing past vblank) lives here. The profiler reports it.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use litex_pac as pac;
+use litex_openfpga::println;
+use crate::timing::{self, CPU_HZ};
+
+const WAKE_EARLY:u32 = CPU_HZ / 2000; // Stop sleeping this long (0.5ms) before vblank is due
+const TEST_CYCLES:u32 = 1000; // Timer length for init()'s check that the interrupt arrives
+
+static WOKEN:AtomicBool = AtomicBool::new(false);
+static SLEEP_OK:AtomicBool = AtomicBool::new(false); // init() saw the timer interrupt arrive
+
+#[export_name = "MachineExternal"]
+extern "C" fn machine_external() {
+    let timer = unsafe { pac::Peripherals::steal() }.TIMER0;
+    if timer.ev_pending.read().bits() != 0 {
+        unsafe {
+            timer.en.write(|w| w.bits(0));
+            timer.ev_pending.write(|w| w.bits(1)); // Acknowledge
+        }
+        WOKEN.store(true, Ordering::Release);
+    }
+}
+
+// Set up TIMER0 as a one-shot wakeup and enable its interrupt. Call once at startup.
+pub fn init(timer:&pac::TIMER0) {
+    unsafe {
+        timer.en.write(|w| w.bits(0));
+        timer.reload.write(|w| w.bits(0)); // One-shot
+        timer.ev_pending.write(|w| w.bits(1));
+        timer.ev_enable.write(|w| w.bits(1));
+        #[cfg(target_arch = "riscv32")]
+        core::arch::asm!("csrs 0xBC0, {0}", in(reg) 1u32 << pac::constants::TIMER0_INTERRUPT);
+        riscv::register::mie::set_mext();
+        riscv::register::mstatus::set_mie();
+    }
+
+    // Only trust wfi once the interrupt has been seen to arrive. Spin (interrupts on) well past when
+    // it should have.
+    arm(timer, TEST_CYCLES);
+    let start = timing::now();
+    while !WOKEN.load(Ordering::Acquire) && timing::now() - start < TEST_CYCLES as u64 * 100 { core::hint::spin_loop(); }
+    let ok = WOKEN.load(Ordering::Acquire);
+    SLEEP_OK.store(ok, Ordering::Release);
+    if !ok {
+        unsafe { timer.en.write(|w| w.bits(0)); }
+        println!("Timer interrupt didn't arrive; polling for vblank instead of sleeping");
+    }
+}
+
+// Start TIMER0 counting down cycles
+fn arm(timer:&pac::TIMER0, cycles:u32) {
+    unsafe {
+        timer.en.write(|w| w.bits(0));
+        timer.load.write(|w| w.bits(cycles));
+        WOKEN.store(false, Ordering::Release);
+        timer.en.write(|w| w.bits(1));
+    }
+}
+
+// Sleep (wfi) for about this many cycles, or until some other interrupt. Does nothing if init()
+// found the timer interrupt doesn't work.
+fn sleep(timer:&pac::TIMER0, cycles:u32) {
+    if !SLEEP_OK.load(Ordering::Acquire) { return }
+    arm(timer, cycles);
+    unsafe {
+        // With interrupts masked, an interrupt between the check and wfi still wakes wfi
+        riscv::register::mstatus::clear_mie();
+        if !WOKEN.load(Ordering::Acquire) { riscv::asm::wfi(); }
+        riscv::register::mstatus::set_mie(); // Handler runs here
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameInfo {
+    pub counter: u32, // Video frame counter at this vblank
+    pub cycles: u64,  // Cycle count when this vblank was noticed (see timing::now)
+    pub dropped: u32, // Whole frames missed since the last vblank we saw
+}
+
+pub struct FrameSync {
+    last: Option<FrameInfo>,
+    overdue: bool,   // end_frame() already saw (and so cleared) this vblank's trigger
+    late: bool,      // end_frame() found we ran past vblank
+    pub missed: u32, // Frames dropped or late, in total
+}
+
+impl FrameSync {
+    pub fn new() -> Self {
+        FrameSync { last:None, overdue:false, late:false, missed:0 }
+    }
+
+    // Sleep until the next vblank begins. idle() is called every time we wake up before it, so
+    // other work (audio) can be kept up. frame_cycles is the expected length of a frame.
+    pub fn wait_for_vblank(&mut self, video:&pac::APF_VIDEO, timer:&pac::TIMER0, frame_cycles:u32, mut idle:impl FnMut()) -> FrameInfo {
+        let counter = loop {
+            let status = video.video.read();
+            if status.vblank_triggered().bit() || self.overdue { break status.frame_counter().bits(); }
+
+            idle();
+
+            if let Some(last) = self.last {
+                let due = last.cycles + frame_cycles as u64;
+                let left = due.saturating_sub(timing::now());
+                if left > 2*WAKE_EARLY as u64 && left < 2*frame_cycles as u64 {
+                    sleep(timer, left as u32 - WAKE_EARLY);
+                }
+            }
+        };
+
+        let mut info = FrameInfo { counter, cycles:timing::now(), dropped:0 };
+        if let Some(last) = self.last {
+            let gap = counter as i32 - last.counter as i32;
+            if gap > 1 {
+                info.dropped = (gap-1) as u32;
+                self.missed += 1;
+            } else if self.late {
+                self.missed += 1;
+            }
+        }
+        self.last = Some(info);
+        self.overdue = false;
+        self.late = false;
+        info
+    }
+
+    // Call when the frame's drawing is done, to check it finished inside vblank
+    pub fn end_frame(&mut self, video:&pac::APF_VIDEO) {
+        let status = video.video.read();
+        if !status.vblank_status().bit() { // Status has already gone low
+            self.late = true;
+        }
+        self.overdue = status.vblank_triggered().bit();
+    }
+}
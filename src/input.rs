@@ -0,0 +1,331 @@
+// Controller support: reads all four APF controller ports, tracks new presses, and detects what
+// kind of controller (if any) is plugged into each port.
+// Notes:
+//    The Pocket's built-in buttons appear as port 1 when undocked. When docked, ports 1-4 are the
+//    dock's controllers. Ports with nothing plugged in read as ControllerType::None and all zeroes.
+//    Analog values are only decoded for ControllerType::DockAnalog; for anything else they read 0.
+//    Sticks are unsigned bytes centered near 128 ([7:0] LX, [15:8] LY, [23:16] RX, [31:24] RY in
+//    contN_joy), triggers are unsigned bytes resting at 0 ([7:0] L, [15:8] R in contN_trig).
+//    Game code should prefer Actions (held/triggered) over raw buttons (down/pressed) so that
+//    players can remap buttons. Each port has its own Bindings.
+
+use litex_pac as pac;
+use litex_openfpga::println;
+use glam::IVec2;
+
+pub const CONTROLLER_COUNT:usize = 4;
+
+pub const ANALOG_MAX:i32 = 127; // Decoded stick axes range -ANALOG_MAX..=ANALOG_MAX, triggers 0..=ANALOG_MAX
+
+// Bitmask for low 16 bits of contN_key
+#[repr(u16)]
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)] // The full button map, though default bindings don't use every button
+pub enum PocketControls {
+    DpadUp     = 1<<0,
+    DpadDown   = 1<<1,
+    DpadLeft   = 1<<2,
+    DpadRight  = 1<<3,
+    FaceA      = 1<<4,
+    FaceB      = 1<<5,
+    FaceX      = 1<<6,
+    FaceY      = 1<<7,
+    TrigL1     = 1<<8,
+    TrigR1     = 1<<9,
+    TrigL2     = 1<<10,
+    TrigR2     = 1<<11,
+    TrigL3     = 1<<12,
+    TrigR3     = 1<<13,
+    FaceSelect = 1<<14,
+    FaceStart  = 1<<15,
+}
+
+impl PocketControls {
+    // Short display name of the lowest button set in a bitmask
+    pub fn name(mask:u16) -> &'static str {
+        const NAMES:[&str;16] = ["UP", "DOWN", "LEFT", "RIGHT", "A", "B", "X", "Y",
+            "L1", "R1", "L2", "R2", "L3", "R3", "SELECT", "START"];
+        if mask == 0 { return "NONE" }
+        NAMES[mask.trailing_zeros() as usize]
+    }
+}
+
+// Things a player can do, independent of which button does them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    MoveLeft,
+    MoveRight,
+    Pause,
+    Launch,
+    Menu,
+}
+
+pub const ACTION_COUNT:usize = 5;
+
+impl Action {
+    pub const ALL:[Action; ACTION_COUNT] = [Action::MoveLeft, Action::MoveRight, Action::Pause, Action::Launch, Action::Menu];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::MoveLeft => "MOVE LEFT",
+            Action::MoveRight => "MOVE RIGHT",
+            Action::Pause => "PAUSE",
+            Action::Launch => "LAUNCH",
+            Action::Menu => "MENU",
+        }
+    }
+}
+
+// Which buttons (PocketControls bitmask) trigger each action, indexed by Action
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bindings {
+    pub buttons: [u16; ACTION_COUNT]
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        use PocketControls::*;
+        Self { buttons: [DpadLeft as u16, DpadRight as u16, FaceSelect as u16, FaceA as u16, FaceStart as u16] }
+    }
+}
+
+impl Bindings {
+    // Convert bitmask of buttons to bitmask of actions (bit n is Action::ALL[n])
+    fn actions(&self, key:u16) -> u8 {
+        let mut actions = 0;
+        for (idx, buttons) in self.buttons.iter().enumerate() {
+            if key & buttons != 0 { actions |= 1<<idx; }
+        }
+        actions
+    }
+}
+
+// High 4 bits of contN_key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ControllerType {
+    #[default]
+    None,         // Nothing connected
+    Pocket,       // Pocket built-in buttons
+    DockGamepad,  // Dock gamepad, digital only
+    DockAnalog,   // Dock gamepad with analog sticks
+    DockKeyboard,
+    DockMouse,
+    Unknown(u8),  // Reserved values
+}
+
+impl ControllerType {
+    pub fn from_key(key:u32) -> Self {
+        match key >> 28 {
+            0 => ControllerType::None,
+            1 => ControllerType::Pocket,
+            2 => ControllerType::DockGamepad,
+            3 => ControllerType::DockAnalog,
+            4 => ControllerType::DockKeyboard,
+            5 => ControllerType::DockMouse,
+            x => ControllerType::Unknown(x as u8),
+        }
+    }
+
+    pub fn connected(&self) -> bool {
+        *self != ControllerType::None
+    }
+}
+
+// Converts raw analog bytes to signed values
+#[derive(Debug, Clone, Copy)]
+pub struct AnalogCalibration {
+    pub center: IVec2,   // Raw value of each stick axis at rest
+    pub range: i32,      // Raw distance from center that counts as fully tilted
+    pub deadzone: i32,   // Raw distance from center (or from 0, for triggers) that counts as no input
+}
+
+impl Default for AnalogCalibration {
+    fn default() -> Self {
+        Self { center:IVec2::splat(128), range:112, deadzone:16 }
+    }
+}
+
+impl AnalogCalibration {
+    // Rescale so output starts at 0 just outside the deadzone and reaches ANALOG_MAX at range
+    fn scale(&self, distance:i32) -> i32 {
+        if distance <= self.deadzone { return 0 }
+        let span = (self.range - self.deadzone).max(1);
+        ((distance - self.deadzone) * ANALOG_MAX / span).min(ANALOG_MAX)
+    }
+
+    // Stick from two raw bytes (x, y). Each axis decoded independently (square deadzone).
+    pub fn stick(&self, raw:u16) -> IVec2 {
+        let raw = IVec2::new((raw & 0xFF) as i32, (raw >> 8) as i32);
+        let offset = raw - self.center;
+        IVec2::new(
+            offset.x.signum() * self.scale(offset.x.abs()),
+            offset.y.signum() * self.scale(offset.y.abs()),
+        )
+    }
+
+    // Trigger from one raw byte, 0..=ANALOG_MAX
+    pub fn trigger(&self, raw:u8) -> i32 {
+        // Triggers travel 0-255 rather than center+-range
+        self.scale(raw as i32 / 2)
+    }
+}
+
+// State of one controller port for the current frame
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Controller {
+    pub kind: ControllerType,
+    pub key: u16,      // Bitmask of buttons currently down (crops out type bits)
+    pub key_edge: u16, // Bitmask is 1 iff a button press is *new this frame*
+    pub actions: u8,      // Bitmask of actions currently held, per bindings
+    pub actions_edge: u8, // Bitmask of actions *new this frame*
+    pub bindings: Bindings,
+    pub stick_left: IVec2,  // Decoded analog sticks, +x right and +y down
+    pub stick_right: IVec2,
+    pub trigger_left: i32,  // Decoded analog triggers
+    pub trigger_right: i32,
+    pub calibration: AnalogCalibration,
+}
+
+impl Controller {
+    // Takes raw contN_key, contN_joy, contN_trig register values
+    fn update(&mut self, raw:u32, joy:u32, trig:u32) {
+        let key = raw as u16;
+        let kind_last = self.kind;
+        self.kind = ControllerType::from_key(raw);
+        self.key_edge = (!self.key) & key;
+        self.key = key;
+        self.actions = self.bindings.actions(key);
+        self.actions_edge = self.bindings.actions(self.key_edge);
+
+        if self.kind == ControllerType::DockAnalog {
+            // Assume sticks are at rest at the moment the controller appears
+            if kind_last != ControllerType::DockAnalog { self.calibrate_center(joy); }
+
+            let cal = self.calibration;
+            self.stick_left = cal.stick(joy as u16);
+            self.stick_right = cal.stick((joy >> 16) as u16);
+            self.trigger_left = cal.trigger(trig as u8);
+            self.trigger_right = cal.trigger((trig >> 8) as u8);
+        } else {
+            self.stick_left = IVec2::ZERO;
+            self.stick_right = IVec2::ZERO;
+            self.trigger_left = 0;
+            self.trigger_right = 0;
+        }
+    }
+
+    // Take current raw left stick value (contN_joy) as the rest position
+    pub fn calibrate_center(&mut self, joy:u32) {
+        self.calibration.center = IVec2::new((joy & 0xFF) as i32, ((joy >> 8) & 0xFF) as i32);
+    }
+
+    // Button is held
+    pub fn down(&self, control:PocketControls) -> bool {
+        self.key & control as u16 != 0
+    }
+
+    // Button was pressed this frame
+    pub fn pressed(&self, control:PocketControls) -> bool {
+        self.key_edge & control as u16 != 0
+    }
+
+    // Some button bound to action is held
+    pub fn held(&self, action:Action) -> bool {
+        self.actions & (1<<action as u8) != 0
+    }
+
+    // Some button bound to action was pressed this frame
+    pub fn triggered(&self, action:Action) -> bool {
+        self.actions_edge & (1<<action as u8) != 0
+    }
+
+    // For a controller with no port behind it (see ai.rs): set the actions held, as a bitmask like
+    // `actions`. New ones since the last call are triggered.
+    pub fn set_actions(&mut self, actions:u8) {
+        self.actions_edge = !self.actions & actions;
+        self.actions = actions;
+    }
+}
+
+// All four controller ports. Call poll() once per frame.
+#[derive(Debug, Default)]
+pub struct Inputs {
+    pub controllers: [Controller; CONTROLLER_COUNT]
+}
+
+impl Inputs {
+    pub fn new() -> Self { Default::default() }
+
+    pub fn poll(&mut self, input:&pac::APF_INPUT) {
+        let raw = [
+            (input.cont1_key.read().bits(), input.cont1_joy.read().bits(), input.cont1_trig.read().bits()),
+            (input.cont2_key.read().bits(), input.cont2_joy.read().bits(), input.cont2_trig.read().bits()),
+            (input.cont3_key.read().bits(), input.cont3_joy.read().bits(), input.cont3_trig.read().bits()),
+            (input.cont4_key.read().bits(), input.cont4_joy.read().bits(), input.cont4_trig.read().bits()),
+        ];
+
+        for (idx, (controller, (key, joy, trig))) in self.controllers.iter_mut().zip(raw).enumerate() {
+            let kind_last = controller.kind;
+            controller.update(key, joy, trig);
+            if controller.kind != kind_last {
+                println!("Controller {}: {:?}", idx+1, controller.kind);
+            }
+        }
+    }
+
+    // Input handle for a player driven by the given port (0-3)
+    pub fn player(&self, port:usize) -> &Controller {
+        &self.controllers[port]
+    }
+
+    // Number of ports with something plugged in
+    pub fn connected_count(&self) -> usize {
+        self.controllers.iter().filter(|c| c.kind.connected()).count()
+    }
+
+    // Some button was pressed this frame on any port
+    pub fn any_pressed(&self) -> bool {
+        self.controllers.iter().any(|c| c.key_edge != 0)
+    }
+
+    // First port on which action was triggered this frame (for "system" actions like pause)
+    pub fn any_triggered(&self, action:Action) -> Option<usize> {
+        self.controllers.iter().position(|c| c.triggered(action))
+    }
+
+    pub fn bindings(&self) -> [Bindings; CONTROLLER_COUNT] {
+        self.controllers.map(|c| c.bindings)
+    }
+
+    pub fn set_bindings(&mut self, bindings:[Bindings; CONTROLLER_COUNT]) {
+        for (controller, bindings) in self.controllers.iter_mut().zip(bindings) {
+            controller.bindings = bindings;
+        }
+    }
+}
+
+// Unit tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn analog_decode() {
+        let cal = AnalogCalibration::default();
+        let raw = |x:i32, y:i32| (x as u16) | ((y as u16) << 8);
+
+        assert_eq!(cal.stick(raw(128, 128)), IVec2::ZERO, "Center should read zero");
+        assert_eq!(cal.stick(raw(128+cal.deadzone, 128-cal.deadzone)), IVec2::ZERO, "Deadzone should read zero");
+        assert_eq!(cal.stick(raw(255, 0)), IVec2::new(ANALOG_MAX, -ANALOG_MAX), "Extremes should saturate");
+        let half = cal.stick(raw(128 + (cal.range+cal.deadzone)/2, 128));
+        assert!(half.x > ANALOG_MAX/3 && half.x < ANALOG_MAX*2/3, "Half tilt should be roughly half: {:?}", half);
+
+        assert_eq!(cal.trigger(0), 0);
+        assert_eq!(cal.trigger(255), ANALOG_MAX);
+
+        let mut off = cal; // Stick that rests off-center
+        off.center = IVec2::new(140, 120);
+        assert_eq!(off.stick(raw(140, 120)), IVec2::ZERO, "Calibrated center should read zero");
+    }
+}
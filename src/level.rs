@@ -0,0 +1,176 @@
+// Built-in levels. Levels are converted from text at build time (see levelfile.rs for the text
+// format and levelformat.rs for the binary one) and read here straight out of the embedded binary.
+// Notes:
+//    Level data is trusted (it was validated by the converter), but a bad version byte is refused.
+//    Levels are numbered from 0 in filename order of levels/*.txt.
+
+use glam::IVec2;
+use crate::levelformat::*;
+
+const LEVELS:&[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/levels.mbl"));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Brick {
+    Normal(u8), // Hits it takes to destroy
+    Steel,      // Indestructible, only reflects
+    Bomb,       // Destroys its neighbors (8 around it) with it
+}
+
+impl Brick {
+    fn from_cell(cell:u8) -> Option<Brick> {
+        match cell {
+            BRICK_STEEL => Some(Brick::Steel),
+            BRICK_BOMB => Some(Brick::Bomb),
+            BRICK_NONE => None,
+            hits => Some(Brick::Normal(hits - BRICK_NORMAL + 1)),
+        }
+    }
+
+    // Counts toward clearing the level
+    pub fn breakable(self) -> bool { self != Brick::Steel }
+}
+
+// Whole grid moves side to side, coming down at the screen edges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct March {
+    pub period: i32, // Logic steps per pixel sideways
+    pub drop: i32,   // Pixels down at each edge
+}
+
+// One brick moves back and forth between its grid spot and path away from it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Patrol {
+    pub col: i32,
+    pub row: i32,
+    pub path: IVec2,
+    pub period: i32, // Logic steps per pixel
+}
+
+// View of a binary level
+#[derive(Clone, Copy)]
+pub struct Level {
+    pub name: &'static str,
+    pub brick: IVec2,   // Size of one brick
+    pub padding: IVec2, // Space between bricks
+    pub top: i32,       // y of the top row
+    pub cols: i32,
+    pub rows: i32,
+    pub march: Option<March>,
+    pub runaway: bool, // Last breakable brick breaks formation and runs around
+    pub shoot: Option<i32>, // Breakable bricks fire at the paddles, every this many logic steps with all of them left
+    cells: &'static [u8],
+    patrols: &'static [u8], // PATROL_SIZE bytes each
+}
+
+impl Level {
+    fn parse(data:&'static [u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE || data[0] != VERSION { return None }
+        let name_len = data[8] as usize;
+        let name = core::str::from_utf8(data.get(HEADER_SIZE..HEADER_SIZE+name_len)?).ok()?;
+        let (cols, rows) = (data[6] as i32, data[7] as i32);
+        let cells_at = HEADER_SIZE+name_len;
+        let cells = data.get(cells_at..cells_at+(cols*rows) as usize)?;
+        let behavior = data.get(cells_at+cells.len()..cells_at+cells.len()+BEHAVIOR_SIZE)?;
+        let patrols_at = cells_at+cells.len()+BEHAVIOR_SIZE;
+        let patrols = data.get(patrols_at..patrols_at+behavior[4] as usize*PATROL_SIZE)?;
+        Some(Level {
+            name,
+            brick: IVec2::new(data[1] as i32, data[2] as i32),
+            padding: IVec2::new(data[3] as i32, data[4] as i32),
+            top: data[5] as i32,
+            cols, rows,
+            march: (behavior[0] > 0).then_some(March { period:behavior[0] as i32, drop:behavior[1] as i32 }),
+            runaway: behavior[2] != 0,
+            shoot: (behavior[3] > 0).then_some(behavior[3] as i32),
+            cells, patrols,
+        })
+    }
+
+    // Brick at a grid position, None if empty
+    pub fn cell(&self, col:i32, row:i32) -> Option<Brick> {
+        Brick::from_cell(self.cells[(row*self.cols + col) as usize])
+    }
+
+    // Upper left pixel position of the brick at a grid position, with the grid centered on a screen this wide
+    pub fn brick_ul(&self, col:i32, row:i32, screen_width:i32) -> IVec2 {
+        let width = self.cols*self.brick.x + (self.cols-1)*self.padding.x;
+        IVec2::new((screen_width - width)/2, self.top) + IVec2::new(col, row)*(self.brick + self.padding)
+    }
+
+    // Patrol for the brick at a grid position, if it has one
+    pub fn patrol(&self, col:i32, row:i32) -> Option<Patrol> {
+        self.patrols.chunks_exact(PATROL_SIZE).map(|p| Patrol {
+            col: p[0] as i32,
+            row: p[1] as i32,
+            path: IVec2::new(p[2] as i8 as i32, p[3] as i8 as i32),
+            period: p[4] as i32,
+        }).find(|patrol| patrol.col == col && patrol.row == row)
+    }
+
+    // All bricks: column, row, brick
+    pub fn bricks(&self) -> impl Iterator<Item=(i32, i32, Brick)> + '_ {
+        (0..self.rows).flat_map(move |row| (0..self.cols).filter_map(move |col| Some((col, row, self.cell(col, row)?))))
+    }
+}
+
+pub fn count() -> usize {
+    LEVELS.first().copied().unwrap_or(0) as usize
+}
+
+// Level by number, or None past the last one
+pub fn get(idx:usize) -> Option<Level> {
+    if idx >= count() { return None }
+    let mut at = 1;
+    for _ in 0..idx {
+        let len = u16::from_le_bytes([LEVELS[at], LEVELS[at+1]]) as usize;
+        at += 2 + len;
+    }
+    let len = u16::from_le_bytes([LEVELS[at], LEVELS[at+1]]) as usize;
+    Level::parse(&LEVELS[at+2..at+2+len])
+}
+
+// Unit tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_in_levels() {
+        assert!(count() > 0, "Should have at least one level");
+        for idx in 0..count() {
+            let level = get(idx).expect("Every level should parse");
+            assert!(level.bricks().any(|(_, _, brick)| brick.breakable()), "Level {} should have breakable bricks", idx);
+        }
+        assert!(get(count()).is_none());
+
+        let first = get(0).unwrap();
+        assert_eq!((first.cols, first.rows), (8, 4), "First level should be the original board");
+        assert_eq!(first.brick_ul(0, 0, 266), IVec2::new(18, 20));
+        assert_eq!(first.brick_ul(1, 1, 266), IVec2::new(48, 52));
+        assert_eq!(first.cell(0, 0), Some(Brick::Normal(1)));
+        assert!(first.march.is_none() && first.patrol(0, 0).is_none() && first.runaway && first.shoot.is_none(), "First level should keep still");
+        assert!((0..count()).any(|idx| get(idx).unwrap().march.is_some()), "Some level should march");
+        assert!((0..count()).any(|idx| get(idx).unwrap().shoot.is_some()), "Some level should shoot");
+
+        assert_eq!(Brick::from_cell(3), Some(Brick::Normal(3)));
+        assert_eq!(Brick::from_cell(BRICK_BOMB), Some(Brick::Bomb));
+    }
+
+    #[test]
+    fn converted_level() { // The converter and the reader agree on the format
+        let text = "name MINI\nmarch 2 5\nrunaway 0\npatrol 1 1 0 -6 4\ngrid\n3.\nX*\n";
+        let data = alloc::vec::Vec::leak(crate::levelfile::convert(text, 266, 240).unwrap());
+        let level = Level::parse(data).unwrap();
+        assert_eq!((level.name, level.cols, level.rows), ("MINI", 2, 2));
+        assert_eq!(level.bricks().collect::<alloc::vec::Vec<_>>(), [(0, 0, Brick::Normal(3)), (0, 1, Brick::Steel), (1, 1, Brick::Bomb)]);
+        assert_eq!(level.march, Some(March { period:2, drop:5 }));
+        assert!(!level.runaway && level.shoot.is_none());
+        assert_eq!(level.patrol(1, 1), Some(Patrol { col:1, row:1, path:IVec2::new(0, -6), period:4 }));
+
+        let mut old = data.to_vec();
+        old[0] = VERSION - 1;
+        assert!(Level::parse(alloc::vec::Vec::leak(old)).is_none(), "Other versions are refused");
+        assert!(Level::parse(&data[..data.len()-1]).is_none(), "Truncated data is refused");
+    }
+}
@@ -0,0 +1,192 @@
+// Host-side converter from level text files (levels/*.txt) to the compact binary format read by
+// level.rs. This file is used by build.rs only, and is not part of the app.
+//
+// Text format. Blank lines and anything after '#' are ignored, except inside the grid.
+//    name FIRST CONTACT      Shown when the level starts (uppercase, see font.rs for characters)
+//    brick 20 12             Optional: brick size in pixels (default 20 12)
+//    padding 10 20           Optional: space between bricks (default 10 20)
+//    top 20                  Optional: y of the top row (default 20)
+//    march 4 8               Optional: the whole grid marches sideways a pixel every 4 logic steps,
+//                            and comes down 8 pixels each time it reaches a screen edge
+//    patrol 2 1 -40 0 3      Optional, any number: the brick at column 2, row 1 (from 0) patrols back
+//                            and forth between its spot and 40 pixels left of it, a pixel every 3 steps
+//    runaway 0               Optional: 1 (the default) if the last breakable brick breaks formation and
+//                            runs around, 0 if it stays put
+//    shoot 90                Optional: breakable bricks fire down at the paddles every 90 logic steps,
+//                            faster as they're destroyed. A shot that hits a paddle costs a life.
+//    grid                    Everything after this line is the grid, one line per row of bricks.
+//    #.##.##.                All rows must be the same width. The grid is centered horizontally.
+//                            "." is an empty space, "#" is a brick, "2" and "3" are bricks that
+//                            take that many hits, "X" is an indestructible brick, "*" is a
+//                            brick that explodes, destroying its neighbors.
+//
+// See levelformat.rs for the binary format.
+
+use crate::levelformat::*;
+
+fn parse_num(s:Option<&str>, what:&str) -> Result<u8, String> {
+    let s = s.ok_or_else(|| format!("missing {what}"))?;
+    s.parse().map_err(|_| format!("bad {what} \"{s}\" (should be 0-255)"))
+}
+
+fn parse_signed(s:Option<&str>, what:&str) -> Result<i8, String> {
+    let s = s.ok_or_else(|| format!("missing {what}"))?;
+    s.parse().map_err(|_| format!("bad {what} \"{s}\" (should be -128-127)"))
+}
+
+fn parse_cell(c:char) -> Result<u8, String> {
+    match c {
+        '.' => Ok(BRICK_NONE),
+        '#' | '1' => Ok(BRICK_NORMAL),
+        '2' => Ok(BRICK_NORMAL+1),
+        '3' => Ok(BRICK_NORMAL+2),
+        'X' => Ok(BRICK_STEEL),
+        '*' => Ok(BRICK_BOMB),
+        _ => Err(format!("unknown grid character '{c}'")),
+    }
+}
+
+// Text level to binary level. Fails if the grid won't fit in the top 2/3 of a screen of this size.
+pub fn convert(text:&str, screen_width:u32, screen_height:u32) -> Result<Vec<u8>, String> {
+    let mut name = String::new();
+    let mut brick = (20u8, 12u8);
+    let mut padding = (10u8, 20u8);
+    let mut top = 20u8;
+    let mut march = (0u8, 0u8);
+    let mut patrols:Vec<[u8; PATROL_SIZE]> = Vec::new();
+    let mut runaway = 1u8;
+    let mut shoot = 0u8;
+    let mut grid:Vec<Vec<u8>> = Vec::new();
+    let mut in_grid = false;
+
+    for (line_idx, line) in text.lines().enumerate() {
+        let fail = |e:String| format!("line {}: {}", line_idx+1, e);
+
+        if in_grid {
+            let row = line.trim();
+            if row.is_empty() { continue }
+            grid.push(row.chars().map(parse_cell).collect::<Result<_,_>>().map_err(fail)?);
+            continue;
+        }
+
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() { continue }
+        let mut words = line.split_whitespace();
+        match words.next().unwrap() {
+            "name" => name = words.collect::<Vec<_>>().join(" "),
+            "brick" => brick = (parse_num(words.next(), "brick width").map_err(fail)?, parse_num(words.next(), "brick height").map_err(fail)?),
+            "padding" => padding = (parse_num(words.next(), "padding x").map_err(fail)?, parse_num(words.next(), "padding y").map_err(fail)?),
+            "top" => top = parse_num(words.next(), "top").map_err(fail)?,
+            "march" => march = (parse_num(words.next(), "march period").map_err(fail)?, parse_num(words.next(), "march drop").map_err(fail)?),
+            "patrol" => patrols.push([
+                parse_num(words.next(), "patrol column").map_err(fail)?,
+                parse_num(words.next(), "patrol row").map_err(fail)?,
+                parse_signed(words.next(), "patrol dx").map_err(fail)? as u8,
+                parse_signed(words.next(), "patrol dy").map_err(fail)? as u8,
+                parse_num(words.next(), "patrol period").map_err(fail)?,
+            ]),
+            "runaway" => runaway = parse_num(words.next(), "runaway").map_err(fail)?.min(1),
+            "shoot" => shoot = parse_num(words.next(), "shoot period").map_err(fail)?,
+            "grid" => in_grid = true,
+            word => return Err(fail(format!("unknown command \"{word}\""))),
+        }
+    }
+
+    // Validate
+    let rows = grid.len();
+    let cols = grid.first().map_or(0, |row| row.len());
+    if rows == 0 || cols == 0 { return Err("grid is empty".into()) }
+    if rows > 255 || cols > 255 { return Err("grid is too big".into()) }
+    if grid.iter().any(|row| row.len() != cols) { return Err("grid rows are not all the same width".into()) }
+    if grid.iter().flatten().all(|&cell| cell == BRICK_NONE || cell == BRICK_STEEL) { return Err("grid has no breakable bricks".into()) }
+    if brick.0 == 0 || brick.1 == 0 { return Err("brick size must be nonzero".into()) }
+    if name.len() > 255 { return Err("name too long".into()) }
+    let width = cols as u32*brick.0 as u32 + (cols as u32-1)*padding.0 as u32;
+    let bottom = top as u32 + rows as u32*brick.1 as u32 + (rows as u32-1)*padding.1 as u32;
+    if width > screen_width { return Err(format!("grid is {width} pixels wide, screen is only {screen_width}")) }
+    if bottom > screen_height*2/3 { return Err(format!("grid reaches y={bottom}, too close to the paddles")) }
+    if patrols.len() > 255 { return Err("too many patrols".into()) }
+    for &[col, row, dx, dy, period] in &patrols {
+        let (col, row, dx, dy) = (col as usize, row as usize, dx as i8 as i32, dy as i8 as i32);
+        if row >= rows || col >= cols || grid[row][col] == BRICK_NONE { return Err(format!("patrol at {col} {row} has no brick to move")) }
+        if period == 0 { return Err(format!("patrol at {col} {row} needs a period of at least 1")) }
+        if dx == 0 && dy == 0 { return Err(format!("patrol at {col} {row} doesn't go anywhere")) }
+        let x = (screen_width - width) as i32/2 + (col*(brick.0 as usize + padding.0 as usize)) as i32 + dx;
+        let y = top as i32 + (row*(brick.1 as usize + padding.1 as usize)) as i32 + dy;
+        if x < 0 || x + brick.0 as i32 > screen_width as i32 || y < 0 || y + brick.1 as i32 > (screen_height*2/3) as i32 {
+            return Err(format!("patrol at {col} {row} leaves the play area"));
+        }
+    }
+
+    let mut out = vec![VERSION, brick.0, brick.1, padding.0, padding.1, top, cols as u8, rows as u8, name.len() as u8];
+    assert_eq!(out.len(), HEADER_SIZE);
+    out.extend(name.as_bytes());
+    for row in &grid { out.extend(row); }
+    let behavior:[u8; BEHAVIOR_SIZE] = [march.0, march.1, runaway, shoot, patrols.len() as u8];
+    out.extend(behavior);
+    for patrol in &patrols { out.extend(patrol); }
+    Ok(out)
+}
+
+// Several binary levels to the single file the app embeds
+pub fn pack(levels:&[Vec<u8>]) -> Result<Vec<u8>, String> {
+    if levels.is_empty() || levels.len() > 255 { return Err("need 1-255 levels".into()) }
+    let mut out = vec![levels.len() as u8];
+    for level in levels {
+        let len:u16 = level.len().try_into().map_err(|_| "level too big".to_string())?;
+        out.extend(len.to_le_bytes());
+        out.extend(level);
+    }
+    Ok(out)
+}
+
+// Unit tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEVEL:&str = "# Comment\nname TEST LEVEL\nbrick 20 10\npadding 2 4\ntop 30\npatrol 1 0 -10 0 3\nshoot 60\ngrid\n\
+        #2X\n.*3\n";
+
+    // Convert LEVEL on a 266x240 screen with one line swapped for another (or added, if from is empty), expecting an error
+    fn error(from:&str, to:&str) -> String {
+        let text = if from.is_empty() { format!("{to}\n{LEVEL}") } else { LEVEL.replacen(from, to, 1) };
+        convert(&text, 266, 240).expect_err(to)
+    }
+
+    #[test]
+    fn converts() {
+        let out = convert(LEVEL, 266, 240).unwrap();
+        assert_eq!(out[..HEADER_SIZE], [VERSION, 20, 10, 2, 4, 30, 3, 2, 10], "Header");
+        assert_eq!(&out[HEADER_SIZE..HEADER_SIZE+10], b"TEST LEVEL");
+        let cells = HEADER_SIZE+10;
+        assert_eq!(out[cells..cells+6], [BRICK_NORMAL, BRICK_NORMAL+1, BRICK_STEEL, BRICK_NONE, BRICK_BOMB, BRICK_NORMAL+2], "Cells");
+        assert_eq!(out[cells+6..cells+6+BEHAVIOR_SIZE], [0, 0, 1, 60, 1], "March, runaway, shoot, patrol count");
+        assert_eq!(out[cells+6+BEHAVIOR_SIZE..], [1, 0, -10i8 as u8, 0, 3], "Patrol");
+
+        let packed = pack(&[out.clone(), out.clone()]).unwrap();
+        assert_eq!(packed[..3], [2, out.len() as u8, 0], "Count and first length");
+        assert_eq!(packed.len(), 1 + 2*(2 + out.len()));
+    }
+
+    #[test]
+    fn rejects() {
+        assert!(error("top 30", "tip 30").contains("line 5: unknown command"));
+        assert!(error("top 30", "top 300").contains("bad top"));
+        assert!(error("top 30", "top").contains("missing top"));
+        assert!(error("-10 0 3", "-200 0 3").contains("bad patrol dx"));
+        assert!(error(".*3", ".*Q").contains("line 10: unknown grid character 'Q'"));
+        assert!(error(".*3", ".*").contains("not all the same width"));
+        assert!(error("#2X\n.*3", "XX.\n..X").contains("no breakable bricks"));
+        assert!(error("grid\n#2X\n.*3\n", "grid\n").contains("grid is empty"));
+        assert!(error("brick 20 10", "brick 0 10").contains("brick size must be nonzero"));
+        assert!(error("brick 20 10", "brick 100 10").contains("screen is only 266"));
+        assert!(error("top 30", "top 150").contains("too close to the paddles"));
+        assert!(error("patrol 1 0", "patrol 0 1").contains("has no brick to move"));
+        assert!(error("-10 0 3", "-10 0 0").contains("needs a period"));
+        assert!(error("-10 0 3", "0 0 3").contains("doesn't go anywhere"));
+        assert!(error("-10 0 3", "-127 0 3").contains("leaves the play area"));
+        assert!(pack(&[]).is_err());
+    }
+}
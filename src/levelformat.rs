@@ -0,0 +1,19 @@
+// Binary level format, shared by the converter (levelfile.rs, run by build.rs) and the reader (level.rs)
+// so the two can't disagree. Keep this file to constants; build.rs includes it too.
+//
+// Layout, all one byte each:
+//    version, brick w, brick h, padding x, padding y, top, cols, rows, name length, name bytes,
+//    then cols*rows cells in row order (BRICK_*),
+//    then march period (0 for none), march drop, runaway, shoot period (0 for none), patrol count,
+//    and for each patrol: col, row, dx, dy (signed), period
+// levels.mbl, the file the app embeds, is a level count then each level as a u16 (little endian)
+// length and its bytes, in filename order.
+
+pub const VERSION:u8 = 3;
+pub const HEADER_SIZE:usize = 9;
+pub const BEHAVIOR_SIZE:usize = 5; // March period and drop, runaway, shoot period, patrol count
+pub const PATROL_SIZE:usize = 5;
+pub const BRICK_NONE:u8 = 0;
+pub const BRICK_NORMAL:u8 = 1; // 1-3 are bricks that take that many hits
+pub const BRICK_STEEL:u8 = 4;
+pub const BRICK_BOMB:u8 = 5;
@@ -1,7 +1,8 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)] // Host builds the unit tests with std
+#![cfg_attr(not(test), no_main)]
 #![allow(unused_parens)]
 
+#[cfg(not(test))]
 use core::panic::PanicInfo;
 use core::slice::from_raw_parts_mut;
 
@@ -10,10 +11,30 @@ extern crate alloc;
 use embedded_alloc::Heap;
 use litex_hal as hal;
 use litex_pac as pac;
-use litex_openfpga::*;
+use litex_openfpga::println;
+#[cfg(not(test))]
 use riscv_rt::entry;
 
+mod ai;
+mod fixed;
+mod font;
+mod frame;
+mod input;
 mod irect2;
+mod level;
+#[cfg(test)]
+mod levelfile; // Build-time converter, here for its unit tests
+mod levelformat;
+mod music;
+mod profile;
+mod rng;
+mod save;
+mod sfx;
+mod songformat;
+mod synth;
+mod timing;
+#[cfg(test)]
+mod tracker; // Build-time converter, here for its unit tests
 
 // Basic platform support
 
@@ -46,6 +67,7 @@ use core::mem::MaybeUninit;
 const HEAP_SIZE: usize = 200 * 1024;
 static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("Panic:");
@@ -54,7 +76,7 @@ fn panic(info: &PanicInfo) -> ! {
     loop {}
 }
 
-#[global_allocator]
+#[cfg_attr(not(test), global_allocator)]
 static HEAP: Heap = Heap::empty();
 
 // Drawing support
@@ -79,32 +101,17 @@ fn pixel(framebuffer_address: *mut u16, x: usize, y: usize) -> &'static mut u16
     &mut framebuffer[y * DISPLAY_WIDTH + x]
 }
 
-// Gamepad controls
-
-#[repr(u16)]
-#[allow(dead_code)]
-enum PocketControls {
-    DpadUp     = 1<<0,
-    DpadDown   = 1<<1,
-    DpadLeft   = 1<<2,
-    DpadRight  = 1<<3,
-    FaceA      = 1<<4,
-    FaceB      = 1<<5,
-    FaceX      = 1<<6,
-    FaceY      = 1<<7,
-    TrigL1     = 1<<8,
-    TrigR1     = 1<<9,
-    TrigL2     = 1<<10,
-    TrigR2     = 1<<11,
-    TrigL3     = 1<<12,
-    TrigR3     = 1<<13,
-    FaceSelect = 1<<14,
-    FaceStart  = 1<<15,
+fn fill(fb: *mut u16, rect:irect2::IRect2, color:u16) { // XOR rectangle with given color
+    for y in rect.ul.y..rect.br.y {
+        for x in rect.ul.x..rect.br.x {
+            *pixel(fb, x as usize,y as usize) ^= color;
+        }
+    }
 }
 
 // This is the entry point for the application.
 // It is not allowed to return.
-#[entry]
+#[cfg_attr(not(test), entry)]
 fn main() -> ! {
     let peripherals = unsafe { pac::Peripherals::steal() };
 
@@ -124,63 +131,236 @@ fn main() -> ! {
         use alloc::vec::Vec;
         use glam::IVec2;
         use crate::irect2::*;
+        use crate::fixed::*;
+        use crate::input::*;
+        use crate::synth::*;
+        use crate::level::{self, Brick, Level};
+        use crate::music::{self, Music};
+        use crate::sfx::*;
+        use crate::timing::{self, Timing};
+        use crate::rng::Rng;
+        use crate::ai::{self, Ai};
+        use crate::frame::{self, FrameSync};
+        use crate::profile::{self, Profiler, Scope};
 
         // Top-level config
 
         const CONFIG_CHAOS:u32 = 0; // 0-2 inclusive, set above 0 for funny pixel garbage effect
         const CONFIG_IMMORTAL:bool = false; // Set true to test without death
 
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[allow(dead_code)]
+        enum GameMode {
+            Single, // One paddle vs. vaders
+            Coop,   // Two paddles sharing the bottom vs. vaders
+            Versus, // Paddles at bottom and top, Pong-style, no vaders
+        }
+        const CONFIG_MODE:Option<GameMode> = None; // Mode selected at first; None picks Coop if 2+ controllers are connected, otherwise Single
+
+        // Scenes: the app is always in exactly one of these. To change scene, set next_scene; the change
+        // happens at the top of the next frame (see "Scene transitions" in the loop), which draws the new scene.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        enum Scene {
+            Boot,       // Soft restart: screen, audio and game state back to power-on, then on to Title
+            Title,      // Title screen and music
+            ModeSelect, // Menu: 1 player, co-op, versus (or look at high scores)
+            Playing,
+            Paused,     // Pause menu; its Settings item opens the remap screen
+            LevelClear, // Board cleared: a moment to breathe, then the next level
+            GameOver,   // Bloop plays over the frozen game, then the game over menu comes up
+            HighScores,
+        }
+
         // Basic state
 
-        let mut paused = false;
-        let mut dead = false;
-        let mut won = false;
-        let mut cont1_key_last = 0; // State of controller on previous loop
+        let mut scene = Scene::Boot;
+        let mut next_scene = Some(Scene::Boot); // Entering Boot sets everything up (it's also the soft restart)
+        let mut scene_steps = 0; // Logic steps since the current scene began
+        let mut menu_selected = 0; // Cursor in the current scene's menu
+        let mut restart = false; // Entering Playing starts a new game, rather than resuming
+        let mut demo = false; // Playing the title screen demo: the computer plays, and any button goes back to the title
+        let mut won = false; // How the last game ended
+        let mut new_high:Option<usize> = None; // Rank of the high score the last game set, to highlight it
+        let mut inputs = Inputs::new(); // State of all 4 controllers, this frame and previous
+        let mut step_edges = [0u8; CONTROLLER_COUNT]; // Per port, actions triggered since the last logic step saw them
+        let mut remap:Option<Remap> = None; // Set while remap screen is up
         // let mut first_frame = true;
 
         // Display
 
-        cfg_if::cfg_if! {
-            if #[cfg(feature = "speed-debug")] { // State used to detect deadline misses
-                const SPEED_DEBUG_RATE:u32 = 1; // Every frame
-                let mut frame_already_overdue:bool = false;
-                let mut video_frame_counter_last:Option<u32> = None;
-                let mut missed_deadline_count:u32 = 0;
-                let mut missed_deadline_already = false;
+        let mut frame_sync = FrameSync::new(); // Waits for vblank, and tracks deadline misses
+        let mut profiler = Profiler::new(); // Only collects with speed-debug; see profile.rs
+        frame::init(&peripherals.TIMER0);
+
+        let screen = IRect2::new(IVec2::ZERO, IVec2::new(DISPLAY_WIDTH as i32, DISPLAY_HEIGHT as i32));
+
+        const TEXT_COLOR:u16 = 0xFFFF; // Black (inverted)
+        const TEXT_SCALE:i32 = 2;
+        const TITLE_SCALE:i32 = 5;
+        const HUD_SCALE:i32 = 1; // Scores
+        const HUD_MARGIN:i32 = 4;
+
+        // Settings
+
+        let mut settings = save::load(&peripherals.APF_BRIDGE).unwrap_or_else(|| {
+            println!("No saved settings, using defaults");
+            Default::default()
+        });
+        inputs.set_bindings(settings.bindings);
+
+        // Poll once before setup so we know what's plugged in
+        inputs.poll(&peripherals.APF_INPUT);
+
+        let start_mode = |inputs:&Inputs| CONFIG_MODE.unwrap_or(if inputs.connected_count() >= 2 { GameMode::Coop } else { GameMode::Single });
+        let mut mode = start_mode(&inputs);
+        let mut cpu:Option<ai::Difficulty> = None; // In versus, player 2 is the computer at this difficulty
+        let mut mode_picked = (mode, cpu); // Last mode select choice; the demo changes mode and cpu but not this
+
+        const ATTRACT_WAIT:i32 = 600; // Logic steps of nobody touching anything on the title screen before the demo starts
+        const DEMO_DIFFICULTY:ai::Difficulty = ai::Difficulty::HARD; // Show off a little
+        const STAND_IN_DIFFICULTY:ai::Difficulty = ai::Difficulty::NORMAL; // Plays a second paddle nobody has a controller for
+
+        // Menus: up/down (or left/right) on any controller moves the cursor, Launch picks.
+
+        const MODE_MENU:[&str; 7] = ["1 PLAYER", "2 PLAYER CO-OP", "2 PLAYER VERSUS", "VS CPU: EASY", "VS CPU: NORMAL", "VS CPU: HARD", "HIGH SCORES"];
+        const MODE_MENU_MODES:[(GameMode, Option<ai::Difficulty>); 6] = [ // The rest of the menu isn't modes
+            (GameMode::Single, None), (GameMode::Coop, None), (GameMode::Versus, None),
+            (GameMode::Versus, Some(ai::Difficulty::EASY)), (GameMode::Versus, Some(ai::Difficulty::NORMAL)), (GameMode::Versus, Some(ai::Difficulty::HARD)),
+        ];
+        const PAUSE_MENU:[&str; 5] = ["RESUME", "RESTART", "SETTINGS", "QUIT TO TITLE", "RESET SYSTEM"];
+        const GAME_OVER_MENU:[&str; 2] = ["CONTINUE", "PLAY AGAIN"];
+
+        // Cursor movement this frame (-1, 0 or 1), and the port that picked the current item, if any
+        fn menu_input(inputs:&Inputs) -> (i32, Option<usize>) {
+            let mut step = 0;
+            for port in 0..CONTROLLER_COUNT {
+                let cont = inputs.player(port);
+                if cont.pressed(PocketControls::DpadUp) || cont.triggered(Action::MoveLeft) { step = -1 }
+                if cont.pressed(PocketControls::DpadDown) || cont.triggered(Action::MoveRight) { step = 1 }
             }
+            (step, inputs.any_triggered(Action::Launch))
         }
 
-        let screen = IRect2::new(IVec2::ZERO, IVec2::new(DISPLAY_WIDTH as i32, DISPLAY_HEIGHT as i32));
+        // Menu items, centered, the selected one marked
+        fn draw_menu_items(fb: *mut u16, screen:IRect2, top:i32, items:&[&str], selected:usize) {
+            let line = font::GLYPH_ADVANCE.y*TEXT_SCALE;
+            for (idx, item) in items.iter().enumerate() {
+                let s = if idx == selected { alloc::format!("> {} <", item) } else { alloc::format!("  {}  ", item) };
+                font::text_centered(fb, screen, top + line*idx as i32, TEXT_SCALE, TEXT_COLOR, &s);
+            }
+        }
 
-        // Audio properties
+        fn draw_menu(fb: *mut u16, screen:IRect2, title:&str, items:&[&str], selected:usize) { // Clears screen
+            render_init(fb);
+            let line = font::GLYPH_ADVANCE.y*TEXT_SCALE;
+            font::text_centered(fb, screen, line*3, TEXT_SCALE, TEXT_COLOR, title);
+            draw_menu_items(fb, screen, line*6, items, selected);
+        }
 
-        const AUDIO_TARGET:i32 = 48000/60 + 200; // Try to always fill audio buffer to this point
+        // Name of the button bound to Launch, for "press X" prompts (controller 1's binding, though any controller works)
+        fn launch_name(inputs:&Inputs) -> &'static str {
+            PocketControls::name(inputs.player(0).bindings.buttons[Action::Launch as usize])
+        }
 
-        // On audio: There will be three types of sound:
-        // 1. Low pitched hum, volume modified by an LFO. This can be overriden by
-        // 2. A "sound effect" bleep, either high or low, used for wall/object bounces. Overriden by
-        // 3. A pulsating "bloop", used to indicate death or victory
+        fn draw_title(fb: *mut u16, screen:IRect2, best:u32, launch:&str) { // Clears screen
+            render_init(fb);
+            let line = font::GLYPH_ADVANCE.y*TEXT_SCALE;
+            font::text_centered(fb, screen, line*4, TITLE_SCALE, TEXT_COLOR, "MINIBREAK");
+            if best > 0 {
+                font::text_centered(fb, screen, line*10, TEXT_SCALE, TEXT_COLOR, &alloc::format!("HIGH SCORE {}", best));
+            }
+            font::text_centered(fb, screen, screen.br.y - line*3, TEXT_SCALE, TEXT_COLOR, &alloc::format!("PRESS {}", launch));
+        }
+
+        fn draw_high_scores(fb: *mut u16, screen:IRect2, scores:&[u32], new_high:Option<usize>, launch:&str) { // Clears screen
+            render_init(fb);
+            let line = font::GLYPH_ADVANCE.y*TEXT_SCALE;
+            font::text_centered(fb, screen, line*3, TEXT_SCALE, TEXT_COLOR, "HIGH SCORES");
+            for (idx, &score) in scores.iter().enumerate() {
+                let score = if score > 0 { alloc::format!("{}", score) } else { alloc::string::String::from("-") };
+                let new = if new_high == Some(idx) { " NEW!" } else { "" };
+                font::text(fb, IVec2::new(screen.center().x - line*4, line*(6+idx as i32)), TEXT_SCALE, TEXT_COLOR,
+                    &alloc::format!("{}. {}{}", idx+1, score, new));
+            }
+            font::text_centered(fb, screen, screen.br.y - line*3, TEXT_SCALE, TEXT_COLOR, &alloc::format!("PRESS {}", launch));
+        }
+
+        // Remap screen: The pause menu's Settings item lets that controller pick a new button for each action in turn.
+
+        struct Remap {
+            port:usize,
+            action:usize, // Index into Action::ALL of the action waiting for a button
+            bindings:Bindings // New bindings so far
+        }
 
-        const AUDIO_LFO_MAX:u16 = 48000; // Speed (period) of background humming envelope
-        const AUDIO_FREQ_DELTA:u16 = 150; // Step of basic sawtooth wave; increase to increase pitch of bleeps/bloops
-        const AUDIO_REFLECT_BLEEP:u16 = 800*2; // How long (in samples) does a single bleep last?
+        fn draw_remap(fb: *mut u16, screen:IRect2, remap:&Remap) { // Clears screen
+            render_init(fb);
+            let line = font::GLYPH_ADVANCE.y*TEXT_SCALE;
+            font::text_centered(fb, screen, line, TEXT_SCALE, TEXT_COLOR, &alloc::format!("REMAP CONTROLLER {}", remap.port+1));
+            for (idx, action) in Action::ALL.iter().enumerate() {
+                let current = idx == remap.action;
+                let button = if current { "?" } else { PocketControls::name(remap.bindings.buttons[idx]) };
+                let s = alloc::format!("{} {}: {}", if current { ">" } else { " " }, action.name(), button);
+                font::text(fb, IVec2::new(line, line*(3+idx as i32)), TEXT_SCALE, TEXT_COLOR, &s);
+            }
+            font::text_centered(fb, screen, screen.br.y - line*2, TEXT_SCALE, TEXT_COLOR, "PRESS A BUTTON FOR EACH");
+        }
+
+        // Audio properties
 
-        const AUDIO_DEATH_BLOOP_STROBE:u16 = 8*800; // How long is a single bloop pulsate?
-        const AUDIO_DEATH_BLOOP:u16 = AUDIO_DEATH_BLOOP_STROBE*6; // How long is the entire bloop sound effect?
+        const AUDIO_MARGIN:i32 = 200; // Try to always fill audio buffer to a frame plus this many samples
+        const AUDIO_REFILL_CHUNK:i32 = 256; // Don't bother refilling until this far below that
+        const AUDIO_LOWPASS:Option<u32> = Some(lowpass_coefficient(12000)); // Soften the mix a little; None to disable
+
+        // On audio: There are two types of sound, on separate synth voices so they can overlap:
+        // 1. Background music, played from the tracker songs in music/ (see music.rs).
+        // 2. Sound effects (see sfx.rs): a high or low bleep for wall/object bounces, panned toward
+        //    the ball for walls and paddles or toward the brick's column for vaders, and a pulsating
+        //    "bloop" to indicate death or victory.
+
+        const VOICE_MUSIC:usize = VOICE_COUNT - music::MAX_CHANNELS; // Music takes the voices sound effects don't
+        const _:() = assert!(VOICE_MUSIC >= SFX_VOICES, "Music and sound effects overlap");
 
         // Audio state
 
-        let mut audio_wave:u16 = 0; // Sawtooth wave state used for all sounds
-        let mut audio_lfo:u16 = AUDIO_LFO_MAX/4; // "Low frequency oscillator"
-        let mut audio_bleep_high = false; // If true and audio_bleeping, use high frequency
-        let mut audio_bleeping = 0; // Remaining samples to play bleep
-        let mut audio_blooping = 0; // Remaining cycles to play bloop
+        let mut synth = Synth::new();
+        synth.lowpass = AUDIO_LOWPASS;
+        synth.dc_block = true;
+        let mut sfx = SfxPlayer::new();
+        let mut audio_bleep = SfxId::Bounce; // Effect for next bleep
+        let mut audio_bleep_pan = 0; // Stereo position of next bleep
+        let mut audio_blooping = false; // True while bloop plays; the game ends when it's done
+        let mut timing = Timing::new(); // Video refresh rate, measured as we go
+        let audio_fill_level = |timing:&Timing| timing.samples_per_frame as i32 + AUDIO_MARGIN;
+        let mut refill = Refill::new(audio_fill_level(&timing) - AUDIO_REFILL_CHUNK, audio_fill_level(&timing));
+
+        // Background music loops until the game ends. (It starts with each game; see "New game" below.)
+        let mut music = Music::new(VOICE_MUSIC);
+
+        // Top up the audio buffer. This is called at several points in the frame so a slow frame
+        // doesn't starve the audio.
+        fn audio_service(refill:&mut Refill, synth:&mut Synth, music:&mut Music, audio:&pac::APF_AUDIO, profiler:&mut Profiler) {
+            let scope = profiler.switch(Scope::Audio);
+            refill.service(synth, audio, music); // Underruns are counted, and reported by the profiler
+            profiler.switch(scope);
+        }
+
+        // Game is over, so stop the music and bloop
+        fn play_bloop(synth:&mut Synth, music:&mut Music, sfx:&mut SfxPlayer, won:bool) {
+            music.stop(synth);
+            sfx.play(synth, if won { SfxId::Win } else { SfxId::Lose }, 0);
+        }
 
         // Game properties
 
-        // This is a simple brick break game. There are players (paddles), balls, and vaders (blocks).
-        // When balls hit vaders, they are destroyed. When it hits the paddle it bounces left or right,
-        // depending on which side of the paddle it hit. The final vader starts trying to run away.
+        // This is a simple brick break game. You have a few lives; losing the last ball in play costs one and
+        // puts a new ball on the paddle to serve, and with none left the game is over. There are players (paddles), balls, and vaders (blocks).
+        // When balls hit vaders, they are destroyed (some take several hits, some can't be destroyed,
+        // and some explode, taking their neighbors with them). When it hits the paddle it bounces off at an angle
+        // depending on where on the paddle it hit, and which way the paddle was moving. Depending on the level,
+        // vaders march or patrol (see Behavior below), and the final one starts trying to run away. Destroyed vaders sometimes drop a capsule; catch it with a paddle
+        // for a power (see Power below), some of which wear off after a while. Clearing a board moves on to the next level (see levels/ and
+        // level.rs); clearing the last one wins.
 
         // On graphics: All drawing is done via XOR. This is nice and simple (drawing is the same as erasing)
         // and generalizes nicely to sprites. When an object needs to move, it draws itself once at its
@@ -190,343 +370,1227 @@ fn main() -> ! {
         const PLAYER_SIZE:IVec2 = IVec2::new(40, 8);
         const PLAYER_START:IVec2 = IVec2::new(DISPLAY_WIDTH as i32/2, DISPLAY_HEIGHT as i32-20-PLAYER_SIZE.y/2);
         const PLAYER_COLOR:u16 = 0b11111_101010_11111; // Remember colors are RGB 565
-        const PLAYER_SPEED:i32 = 2; // Velocity when button down
+        const PLAYER_SPEED:Fixed = Fixed::from_int(2); // Velocity when button down
+        const PLAYER_SPEED_ANALOG_MAX:i32 = 4; // Velocity when analog stick fully tilted (whole pixels)
+        const PLAYER_COOP_OFFSET:IVec2 = IVec2::new(DISPLAY_WIDTH as i32/6, 0); // Co-op paddles start this far either side of center
+        const PLAYER_START_TOP:IVec2 = IVec2::new(PLAYER_START.x, DISPLAY_HEIGHT as i32-PLAYER_START.y); // Versus player 2
 
         const BALL_SIZE:IVec2 = IVec2::new(4,4);
-        let ball1_start:IVec2 = PLAYER_START + IVec2::new(0, -30); // Initial position
         const BALL_COLOR:u16 = 0b00000_000000_11111 ^ 0xFFFF;
-        const BALL_SPEED:i32 = 3; // Movement per frame. Notice this is faster than the player.
-        const BALL_FACING_START:IVec2 = IVec2::new(1,-1); // Initial velocity
-
-        // Multiply by one of these vectors to reflect on the X or Y axis.
-        const REFLECTS:[IVec2;2] = [ IVec2::new(-1,1), IVec2::new(1,-1) ];
-
-        const VADER_COLS:i32 = 8; // Vaders appear in cols X rows grid
-        const VADER_ROWS:i32 = 4;
-        const VADER_SIZE:IVec2 = IVec2::new(20, 12);
-        const VADER_PADDING:IVec2 = IVec2::new(10,20); // Space between vaders
-        // Upper left pixel position of upper left vader
-        const VADER_ORIGIN:IVec2 = IVec2::new((DISPLAY_WIDTH as i32-(VADER_COLS*VADER_SIZE.x + (VADER_COLS-1)*VADER_PADDING.x))/2, 20);
+        const BALL_SPEED:i32 = 3; // Movement per step on each axis at the start, at 45 degrees. Notice this is faster than the player.
+        const BALL_SPEEDUP:Fixed = Fixed::from_ratio(65, 64); // Ball speed is multiplied by this each time a vader is destroyed...
+        const BALL_SPEED_MAX:Fixed = Fixed::from_int(6); // ...up to this many pixels per step
+        const BALL_ANGLE_MAX:i32 = 60; // Steepest bounce off a paddle, in degrees from straight up/down (keeps the ball off the horizontal)
+        const BALL_ANGLE_ENGLISH:i32 = 15; // Extra degrees of bounce in the direction the paddle is moving
+        const BANNER_WAIT:i32 = 60; // Logic steps the level name shows at the start of a level
+        const SERVE_AIM_MAX:i32 = 45; // Serve aim sweeps back and forth this many degrees either side of straight out...
+        const SERVE_AIM_PERIOD:i32 = 120; // ...once every this many logic steps. Launch serves wherever it points.
+        const SERVE_AIM_DOTS:[i32; 3] = [10, 16, 22]; // Aim indicator: dots this far out from the ball's center
+        const SERVE_AIM_DOT_SIZE:IVec2 = IVec2::new(2,2);
+        const LEVEL_CLEAR_WAIT:i32 = 90; // Logic steps between clearing a board and the next one appearing
+
+        // Vader layout comes from the level (see level.rs)
         const VADER_COLOR:u16 = 0b11111_000000_00000 ^ 0xFFFF;
+        const VADER_COLOR_TOUGH:[u16; 2] = [0b11111_100000_00000 ^ 0xFFFF, 0b10011_000000_11000 ^ 0xFFFF]; // 2 hits left, 3+ hits left
+        const VADER_COLOR_STEEL:u16 = 0b10000_100000_10000 ^ 0xFFFF;
+        const VADER_COLOR_BOMB:u16 = 0b11000_110000_00000 ^ 0xFFFF;
+        const VADER_POINTS:u32 = 10; // Score for destroying one, times hits it took
+        const VADER_POINTS_BOMB:u32 = 25; // Plus whatever it takes with it
+        const COMBO_MAX:u32 = 5; // Each vader destroyed before the ball returns to a paddle multiplies points by one more, up to this
+
+        // Color shows what a vader will do when hit
+        fn vader_color(brick:Brick) -> u16 {
+            match brick {
+                Brick::Normal(0..=1) => VADER_COLOR,
+                Brick::Normal(2) => VADER_COLOR_TOUGH[0],
+                Brick::Normal(_) => VADER_COLOR_TOUGH[1],
+                Brick::Steel => VADER_COLOR_STEEL,
+                Brick::Bomb => VADER_COLOR_BOMB,
+            }
+        }
 
-        assert_eq!(VADER_ORIGIN.x+VADER_PADDING.x >= 0, true, "Screen too narrow for vaders");
+        const VERSUS_WIN_SCORE:u32 = 7; // Points to win versus mode
+
+        const CAPSULE_SIZE:IVec2 = IVec2::new(11, 7);
+        const CAPSULE_COLOR:u16 = 0b00000_101010_00000 ^ 0xFFFF;
+        const CAPSULE_SPEED:Fixed = Fixed::from_ratio(3, 4); // Fall speed, pixels per step
+        const CAPSULE_CHANCE:(u32, u32) = (1, 6); // A destroyed vader drops a capsule this many times in this many
+        const SHOT_SIZE:IVec2 = IVec2::new(2, 6);
+        const SHOT_COLOR:u16 = 0b11111_011000_00000 ^ 0xFFFF;
+        const SHOT_SPEED:Fixed = Fixed::from_ratio(3, 2); // Fall speed, pixels per step
+        const SHOT_PERIOD_MIN:i32 = 20; // However few vaders are left, they don't fire more often than this many logic steps
+        const SHOT_MAX:usize = 3; // Most shots falling at once
+        const POWER_TIME:i32 = 60*15; // Logic steps a timed power lasts
+        const POWER_WIDE:i32 = 60; // Paddle widths for the wide/narrow powers
+        const POWER_NARROW:i32 = 26;
+        const POWER_SLOW:Fixed = Fixed::from_ratio(1, 2); // Ball speed is multiplied by this while slow
+        const POWER_STICKY_HOLD:i32 = 120; // Steps a sticky paddle holds the ball before letting go by itself
+        const POWER_MULTIBALL_ANGLE:i32 = 30; // Multiball splits off balls this many degrees either side
+        const BALL_MAX:usize = 8;
+        const LIVES_START:u32 = 2; // Spare lives (balls after the first) at the start of a game
+        const LIVES_MAX:u32 = 5; // Most spare lives you can hold
+
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        enum Power {
+            Multiball, // Two more balls split off from one in play
+            Wide,      // Timed: catching paddle gets wider...
+            Narrow,    // Timed: ...or narrower (replaces Wide, and vice versa)
+            Slow,      // Timed: all balls move at half speed
+            Sticky,    // Timed: catching paddle holds balls that hit it until Launch
+            Life,      // An extra life
+        }
+
+        impl Power {
+            const ALL:[Power; 6] = [Power::Multiball, Power::Wide, Power::Narrow, Power::Slow, Power::Sticky, Power::Life];
+
+            fn letter(self) -> &'static str {
+                match self {
+                    Power::Multiball => "M",
+                    Power::Wide => "W",
+                    Power::Narrow => "N",
+                    Power::Slow => "S",
+                    Power::Sticky => "G", // Glue
+                    Power::Life => "L",
+                }
+            }
+        }
 
         // Game state
 
         struct Vader { // Block
-            rect:IRect2
+            rect:IRect2,
+            home:IVec2, // Upper left of its spot in the formation, before any marching
+            col:i32, // Level grid position it started in, for stereo placement of its sound and finding bomb neighbors
+            row:i32,
+            brick:Brick, // Normal counts down hits left
+            points:u32, // Score for destroying it
+            behavior:Behavior
+        }
+
+        // How a vader moves. Every vader starts in the formation, which marches if the level says so (see
+        // levels/ and level.rs). Some patrol back and forth from their spot in it. When only one breakable
+        // vader is left, it may break away and run around, so you aren't stuck unable to hit it.
+        #[derive(Clone, Copy)]
+        enum Behavior {
+            Formation,
+            Patrol { path:IVec2, period:i32, at:i32, dir:i32 }, // at: pixels along path (its longer axis), dir: -1 or 1
+            Runaway { facing:i32 }, // -1 or 1 l/r
+        }
+
+        struct Formation {
+            offset:IVec2, // How far the whole grid has marched from where the level put it
+            facing:i32, // -1 or 1 l/r
+            steps:i32, // Logic steps since the level began
+            size:usize, // Breakable vaders the level started with; fire speeds up as they go
+            shot_wait:i32 // Logic steps until the next shot, if the level shoots
         }
+        const FORMATION_START:Formation = Formation { offset:IVec2::ZERO, facing:1, steps:0, size:0, shot_wait:0 };
+        const MARCH_FLOOR:i32 = DISPLAY_HEIGHT as i32*2/3; // The formation stops coming down here, well clear of the paddles
 
         struct Ball {
+            rect:IRect2, // Where it's drawn: pos rounded to the nearest pixel
+            pos:FVec2, // Upper left, sub-pixel
+            velocity:FVec2, // Pixels per step
+            owner:usize, // Index of player who last touched the ball, gets credit for vaders
+            wait:i32, // Caught by a sticky paddle: logic steps until it lets go
+            stuck:Option<(usize, i32)>, // Held on a paddle (serving, or caught by a sticky one): player index, and x offset from the paddle's left edge
+            aim:Option<i32>, // Serving: logic steps spent aiming, which sets the serve angle (see serve_angle)
+            combo:u32 // Vaders destroyed since it last touched a paddle
+        }
+
+        struct Capsule { // Falling power-up
             rect:IRect2,
-            facing:IVec2 // Each axis should be 1 or -1
+            y:Fixed, // Top edge, sub-pixel
+            power:Power
         }
 
-        struct Player {
+        struct Shot { // Falling vader fire
             rect:IRect2,
-            facing:i32 // -1 or 1 l/r, or 0 when still
+            y:Fixed // Top edge, sub-pixel
+        }
+
+        #[derive(Clone, Copy)]
+        struct Effect { // A timed power in effect
+            power:Power,
+            player:usize, // Who caught it (for Slow, doesn't matter)
+            left:i32 // Logic steps until it wears off
+        }
+
+        struct Player {
+            rect:IRect2, // Where it's drawn: x rounded to the nearest pixel
+            x:Fixed, // Left edge, sub-pixel
+            facing:i32, // -1 or 1 l/r, or 0 when still
+            port:usize, // Which controller drives this paddle...
+            ai:Option<Ai>, // ...unless the computer does
+            score:u32
+        }
+
+        // What's driving a paddle this step: its controller, or the computer's.
+        // edges replaces the controller's actions_edge, since a frame can run zero or several logic steps.
+        fn controller(inputs:&Inputs, edges:&[u8; CONTROLLER_COUNT], player:&Player) -> Controller {
+            player.ai.as_ref().map_or_else(|| {
+                let mut cont = *inputs.player(player.port);
+                cont.actions_edge = edges[player.port];
+                cont
+            }, |ai| ai.controller)
         }
 
         let mut vaders: Vec<Vader> = Default::default();
         let mut balls: Vec<Ball> = Default::default();
         let mut players: Vec<Player> = Default::default();
+        let mut capsules: Vec<Capsule> = Default::default();
+        let mut shots: Vec<Shot> = Default::default();
+        let mut effects: Vec<Effect> = Default::default();
+        let mut lives:u32 = 0; // Spare lives (balls after the one in play)
+        let mut rng = Rng::new(peripherals.APF_RTC.unix_seconds.read().bits() ^ timing::now() as u32);
+
+        let new_player = |center:IVec2, port:usize| {
+            let rect = IRect2::new_centered(center, PLAYER_SIZE);
+            Player { rect, x:Fixed::from_int(rect.ul.x), facing:0, port, ai:None, score:0 }
+        };
+        // Serving: a ball starts each life and level sitting on a paddle. It follows the paddle while an
+        // aim indicator sweeps back and forth, and Launch sends it off where the indicator points.
 
-        players.push(Player { rect:IRect2::new_centered(PLAYER_START, PLAYER_SIZE), facing:0 });
+        // Which way a paddle sends balls: -1 up (the bottom paddle), 1 down (the versus top paddle)
+        fn paddle_face(player:&Player) -> i32 {
+            if player.rect.center().y < DISPLAY_HEIGHT as i32/2 { 1 } else { -1 }
+        }
+
+        // Where a ball held on a paddle sits: against its face, offset from its left edge
+        fn held_rect(player:&Player, offset:i32) -> IRect2 {
+            let offset = offset.clamp(0, player.rect.size().x - BALL_SIZE.x); // In case the paddle shrank
+            let y = if paddle_face(player) < 0 { player.rect.ul.y - BALL_SIZE.y } else { player.rect.br.y };
+            let ul = IVec2::new(player.rect.ul.x + offset, y);
+            IRect2::new(ul, ul + BALL_SIZE)
+        }
 
-        let ball_facing_start = { // Randomly start off moving left or right; use the current UTC as a very weak RNG
-            let mut ball_facing = BALL_FACING_START;
-            if 0 == peripherals.APF_RTC.unix_seconds.read().bits() % 2 { ball_facing.x *= -1 }
-            ball_facing
+        // Serve angle in degrees from straight out, after aiming this many steps: a triangle wave that starts
+        // straight out, then sweeps to +SERVE_AIM_MAX, back to -SERVE_AIM_MAX, and so on.
+        fn serve_angle(aim:i32) -> i32 {
+            let half = SERVE_AIM_PERIOD/2;
+            let t = (aim + SERVE_AIM_PERIOD/4) % SERVE_AIM_PERIOD;
+            let t = if t < half { t } else { SERVE_AIM_PERIOD - t };
+            t*2*SERVE_AIM_MAX/half - SERVE_AIM_MAX
+        }
+
+        // Starting speed, as fast as a ball moving BALL_SPEED on each axis
+        let serve_speed = FVec2::from_ivec2(IVec2::splat(BALL_SPEED)).length();
+
+        // New ball sitting on a paddle, ready to serve
+        let serve_ball = |players:&[Player], pidx:usize| {
+            let player = &players[pidx];
+            let offset = (player.rect.size().x - BALL_SIZE.x)/2;
+            let rect = held_rect(player, offset);
+            let velocity = FVec2::new(Fixed::ZERO, serve_speed.with_sign_of(Fixed::from_int(paddle_face(player)))); // Straight out, for multiball
+            Ball { rect, pos:FVec2::from_ivec2(rect.ul), velocity, owner:pidx, wait:0, stuck:Some((pidx, offset)), aim:Some(0), combo:0 }
         };
-        balls.push(Ball { rect:IRect2::new_centered(ball1_start, BALL_SIZE), facing:ball_facing_start });
 
-        let mut final_vader_facing = 0; // Becomes nonzero when 1 vader left
+        // Ball, with its aim indicator if it's serving (XOR, so this also erases)
+        fn draw_ball(fb: *mut u16, ball:&Ball, players:&[Player]) {
+            fill(fb, ball.rect, BALL_COLOR);
+            if let (Some((pidx, _)), Some(aim)) = (ball.stuck, ball.aim) {
+                let face = paddle_face(&players[pidx]);
+                for distance in SERVE_AIM_DOTS {
+                    let out = FVec2::from_angle(serve_angle(aim), Fixed::from_int(distance));
+                    let center = ball.rect.center() + IVec2::new(out.x.round(), out.y.abs().round()*face);
+                    fill(fb, IRect2::new_centered(center, SERVE_AIM_DOT_SIZE), BALL_COLOR);
+                }
+            }
+        }
+
+        let mut formation = FORMATION_START;
+
+        // Levels: vaders are placed from the current level's grid. Versus has no vaders and stays on level 0.
+        let mut level_idx = 0;
+        let mut level = level::get(level_idx).expect("No levels built in");
+        let mut banner:Option<(alloc::string::String, i32)> = None; // Level name in mid-screen, and logic steps until it's erased
+
+        fn load_level(vaders:&mut Vec<Vader>, formation:&mut Formation, level:&Level) {
+            vaders.clear();
+            for (col, row, brick) in level.bricks() {
+                let ul = level.brick_ul(col, row, DISPLAY_WIDTH as i32);
+                let points = match brick {
+                    Brick::Normal(hits) => VADER_POINTS * hits as u32,
+                    Brick::Bomb => VADER_POINTS_BOMB,
+                    Brick::Steel => 0,
+                };
+                let behavior = level.patrol(col, row)
+                    .map_or(Behavior::Formation, |patrol| Behavior::Patrol { path:patrol.path, period:patrol.period, at:0, dir:1 });
+                vaders.push(Vader { rect:IRect2::new(ul, ul+level.brick), home:ul, col, row, brick, points, behavior });
+            }
+            let size = vaders.iter().filter(|vader| vader.brick.breakable()).count();
+            *formation = Formation { size, shot_wait:level.shoot.unwrap_or(0), ..FORMATION_START };
+        }
 
-        for y in 0..VADER_ROWS {
-            for x in 0..VADER_COLS {
-                let ul = VADER_ORIGIN + IVec2::new(x, y)*(VADER_SIZE + VADER_PADDING);
-                vaders.push(Vader { rect:IRect2::new(ul, ul+VADER_SIZE) });
+        // Remove a vader and, if it's a bomb, its breakable neighbors (which may be bombs too).
+        // Returns everything removed, so the caller can erase and score it.
+        fn destroy_vader(vaders:&mut Vec<Vader>, idx:usize) -> Vec<Vader> {
+            let mut gone = alloc::vec![vaders.remove(idx)];
+            let mut next = 0;
+            while next < gone.len() {
+                if gone[next].brick == Brick::Bomb {
+                    let (col, row) = (gone[next].col, gone[next].row);
+                    let mut idx = 0;
+                    while idx < vaders.len() {
+                        let vader = &vaders[idx];
+                        if vader.brick.breakable() && (vader.col - col).abs() <= 1 && (vader.row - row).abs() <= 1 {
+                            gone.push(vaders.remove(idx));
+                        } else {
+                            idx += 1;
+                        }
+                    }
+                }
+                next += 1;
             }
+            gone
+        }
+
+        // Capsule is a box with its power's letter. XOR, so draw again to erase.
+        fn draw_capsule(fb: *mut u16, capsule:&Capsule) {
+            fill(fb, capsule.rect, CAPSULE_COLOR);
+            font::text(fb, capsule.rect.ul + (CAPSULE_SIZE - font::GLYPH_SIZE)/2, 1, TEXT_COLOR, capsule.power.letter());
         }
 
-        // Gfx support
+        // Spare lives, as balls in the lower left. XOR, so draw again with the same count to erase.
+        fn draw_lives(fb: *mut u16, screen:IRect2, lives:u32) {
+            for idx in 0..lives as i32 {
+                let ul = IVec2::new(HUD_MARGIN + idx*(BALL_SIZE.x + 2), screen.br.y - HUD_MARGIN - BALL_SIZE.y);
+                fill(fb, IRect2::new(ul, ul + BALL_SIZE), BALL_COLOR);
+            }
+        }
 
-        fn fill(fb: *mut u16, rect:IRect2, color:u16) { // XOR rectangle with given color
-            for y in rect.ul.y..rect.br.y {
-                for x in rect.ul.x..rect.br.x {
-                    *pixel(fb, x as usize,y as usize) ^= color;
+        // Change paddle width, keeping it centered where it was (but on screen, and in co-op, clear of the
+        // other paddle if there's room)
+        fn resize_player(fb: *mut u16, screen:IRect2, players:&mut [Player], pidx:usize, width:i32) {
+            let mut rect = IRect2::new_centered(players[pidx].rect.center(), IVec2::new(width, PLAYER_SIZE.y)).force_enclose_x(screen);
+            for (other_idx, other) in players.iter().enumerate() {
+                if other_idx != pidx && other.rect.intersect(rect) { // Grew into it: shift away
+                    let dx = if rect.center().x < other.rect.center().x { other.rect.ul.x - rect.br.x } else { other.rect.br.x - rect.ul.x };
+                    rect = rect.offset(IVec2::new(dx, 0)).force_enclose_x(screen);
                 }
             }
+            let player = &mut players[pidx];
+            fill(fb, player.rect, PLAYER_COLOR); // Erase
+            player.rect = rect;
+            player.x = Fixed::from_int(player.rect.ul.x);
+            fill(fb, player.rect, PLAYER_COLOR); // Draw
+        }
+
+        // XOR, so draw again with the same text to erase
+        fn draw_banner(fb: *mut u16, screen:IRect2, text:&str) {
+            font::text_centered(fb, screen, screen.center().y, TEXT_SCALE, TEXT_COLOR, text);
+        }
+        let level_banner = |idx:usize, level:&Level| alloc::format!("LEVEL {}: {}", idx+1, level.name);
+
+        // Scores: P1 upper left, P2 upper right. XOR, so draw again with the same score to erase.
+        fn draw_score(fb: *mut u16, screen:IRect2, idx:usize, score:u32) {
+            let s = alloc::format!("P{} {}", idx+1, score);
+            let x = if idx == 0 { HUD_MARGIN } else { screen.br.x - HUD_MARGIN - font::text_size(&s, HUD_SCALE).x };
+            font::text(fb, IVec2::new(x, HUD_MARGIN), HUD_SCALE, TEXT_COLOR, &s);
+        }
+
+        // Everything in play (clears screen). The level banner isn't included.
+        fn draw_game(fb: *mut u16, screen:IRect2, players:&[Player], balls:&[Ball], vaders:&[Vader], capsules:&[Capsule], lives:u32) {
+            render_init(fb);
+            for player in players { fill(fb, player.rect, PLAYER_COLOR); }
+            for ball in balls { draw_ball(fb, ball, players); }
+            for vader in vaders { fill(fb, vader.rect, vader_color(vader.brick)); }
+            for (idx, player) in players.iter().enumerate() { draw_score(fb, screen, idx, player.score); }
+            for capsule in capsules { draw_capsule(fb, capsule); }
+            draw_lives(fb, screen, lives);
         }
 
-        // Initial draw
-        for player in &players { fill(fb, player.rect, PLAYER_COLOR); }
-        for ball in &balls { fill(fb, ball.rect, BALL_COLOR); }
-        for vader in &vaders { fill(fb, vader.rect, VADER_COLOR); }
+        // Game over screen (clears screen): who won, final scores, how far you got, and the game over menu
+        fn draw_game_over(fb: *mut u16, screen:IRect2, won:bool, players:&[Player], level:Option<usize>, selected:usize) {
+            render_init(fb);
+            let line = font::GLYPH_ADVANCE.y*TEXT_SCALE;
+            let title = match (won, level) {
+                (false, _) => alloc::string::String::from("GAME OVER"),
+                (true, None) => { // Versus
+                    let winner = players.iter().enumerate().max_by_key(|(_, player)| player.score).map_or(0, |(idx, _)| idx);
+                    alloc::format!("P{} WINS", winner+1)
+                }
+                (true, Some(_)) => alloc::string::String::from("YOU WIN"),
+            };
+            font::text_centered(fb, screen, line*3, TEXT_SCALE, TEXT_COLOR, &title);
+            for (idx, player) in players.iter().enumerate() {
+                font::text_centered(fb, screen, line*(5+idx as i32), TEXT_SCALE, TEXT_COLOR, &alloc::format!("P{} {}", idx+1, player.score));
+            }
+            if let Some(level) = level {
+                font::text_centered(fb, screen, line*8, TEXT_SCALE, TEXT_COLOR, &alloc::format!("REACHED LEVEL {}", level+1));
+            }
+            draw_menu_items(fb, screen, line*11, &GAME_OVER_MENU, selected);
+        }
 
         loop {
-            // Busy loop until VBLANK begins, signaling next frame ready to go.
+            // Sleep until VBLANK begins, signaling next frame ready to go.
             // We'd like to do all drawing inside VBLANK to prevent tearing.
-            loop {
-                let video = peripherals.APF_VIDEO.video.read();
-                let frame_ready = video.vblank_triggered().bit();
-
-                // Complex tracking to see if frames were skipped
-                cfg_if::cfg_if! {
-                    if #[cfg(feature = "speed-debug")] {
-                        let frame_ready = frame_ready || frame_already_overdue;
-                        if frame_ready {
-                            let video_frame_counter = video.frame_counter().bits();
-                            if let Some(video_frame_counter_last) = video_frame_counter_last {
-                                let gap = video_frame_counter as i32 - video_frame_counter_last as i32;
-                                if gap > 1 {
-                                    if 0== missed_deadline_count % SPEED_DEBUG_RATE {
-                                        println!("Too slow! Dropped an entire frame (frames missing {}; fail #{})", gap-1, missed_deadline_count);
-                                    }
-                                    missed_deadline_count += 1;
-                                } else {
-                                    if missed_deadline_already { missed_deadline_count += 1 }
-                                    if gap <= 0 {
-                                        println!("Catastrophic failure: Video counts no frames between frames (gap of {})", gap);
-                                    }
+            let frame_info = frame_sync.wait_for_vblank(&peripherals.APF_VIDEO, &peripherals.TIMER0, timing.frame_cycles,
+                || audio_service(&mut refill, &mut synth, &mut music, &peripherals.APF_AUDIO, &mut profiler)); // Use the wait
+
+            let sim_steps = timing.frame(frame_info.counter, frame_info.cycles);
+            refill.high = audio_fill_level(&timing); // Follow the measured refresh rate
+            refill.low = refill.high - AUDIO_REFILL_CHUNK;
+
+            profiler.end_frame(timing.frame_cycles); // Frame boundary is vblank
+            profiler.switch(Scope::Input);
+
+            // Controls
+
+            inputs.poll(&peripherals.APF_INPUT);
+            for (edges, cont) in step_edges.iter_mut().zip(&inputs.controllers) {
+                *edges |= cont.actions_edge; // Held until a logic step runs, so presses aren't dropped
+            }
+
+            use Action::*;
+
+            // Scene transitions: set up and draw the new scene
+            if let Some(next) = next_scene.take() {
+                let from = core::mem::replace(&mut scene, next);
+                scene_steps = 0;
+                menu_selected = 0;
+                let scope = profiler.switch(Scope::Draw);
+                match scene {
+                    Scene::Boot => { // Everything back to how it was at power-on, without resetting the SoC
+                        render_init(fb);
+                        music.stop(&mut synth);
+                        sfx = SfxPlayer::new();
+                        synth.reset();
+                        audio_blooping = false;
+                        remap = None;
+                        step_edges = [0; CONTROLLER_COUNT];
+                        (restart, demo, won, new_high) = (false, false, false, None);
+                        (mode, cpu) = (start_mode(&inputs), None);
+                        mode_picked = (mode, cpu);
+                        players.clear();
+                        balls.clear();
+                        vaders.clear();
+                        capsules.clear();
+                        shots.clear();
+                        effects.clear();
+                        (lives, level_idx, banner) = (0, 0, None);
+                        level = level::get(level_idx).unwrap();
+                        next_scene = Some(Scene::Title);
+                    }
+                    Scene::Title => {
+                        demo = false;
+                        audio_blooping = false;
+                        sfx.stop_all(&mut synth);
+                        music.play(&mut synth, music::TITLE);
+                        draw_title(fb, screen, settings.high_scores[0], launch_name(&inputs));
+                    }
+                    Scene::ModeSelect => {
+                        menu_selected = MODE_MENU_MODES.iter().position(|&picks| picks == mode_picked).unwrap_or(0);
+                        draw_menu(fb, screen, "SELECT MODE", &MODE_MENU, menu_selected);
+                    }
+                    Scene::Playing if restart => { // New game
+                        restart = false;
+                        audio_blooping = false;
+                        // Players are driven by the first connected controllers, in port order
+                        let mut ports = (0..CONTROLLER_COUNT).filter(|&port| inputs.player(port).kind.connected());
+                        let port1 = ports.next().unwrap_or(0);
+                        let port2 = ports.next().unwrap_or(1);
+
+                        players.clear();
+                        match mode {
+                            GameMode::Single => {
+                                players.push(new_player(PLAYER_START, port1));
+                            }
+                            GameMode::Coop => {
+                                players.push(new_player(PLAYER_START - PLAYER_COOP_OFFSET, port1));
+                                players.push(new_player(PLAYER_START + PLAYER_COOP_OFFSET, port2));
+                            }
+                            GameMode::Versus => {
+                                players.push(new_player(PLAYER_START, port1));
+                                players.push(new_player(PLAYER_START_TOP, port2));
+                                if let Some(difficulty) = cpu { players[1].ai = Some(Ai::new(difficulty, rng.next())); }
+                            }
+                        }
+                        if let Some(player) = players.get_mut(1) { // Only one controller: the computer takes the second paddle
+                            if player.ai.is_none() && !inputs.player(player.port).kind.connected() {
+                                player.ai = Some(Ai::new(STAND_IN_DIFFICULTY, rng.next()));
+                            }
+                        }
+                        if demo { players[0].ai = Some(Ai::new(DEMO_DIFFICULTY, rng.next())); }
+                        balls.clear();
+                        balls.push(serve_ball(&players, 0));
+                        vaders.clear();
+                        capsules.clear();
+                        shots.clear();
+                        effects.clear();
+                        formation = FORMATION_START;
+                        level_idx = 0;
+                        level = level::get(level_idx).unwrap();
+                        banner = None;
+                        lives = 0;
+                        if mode != GameMode::Versus {
+                            load_level(&mut vaders, &mut formation, &level);
+                            banner = Some((level_banner(level_idx, &level), BANNER_WAIT));
+                            lives = LIVES_START;
+                        }
+                        if demo { banner = Some((alloc::string::String::from("DEMO"), i32::MAX)); } // Up the whole time
+
+                        sfx.stop_all(&mut synth);
+                        music.play(&mut synth, music::GAME);
+
+                        draw_game(fb, screen, &players, &balls, &vaders, &capsules, lives);
+                        if let Some((text, _)) = &banner { draw_banner(fb, screen, text); }
+                    }
+                    Scene::Playing => if from == Scene::Paused { // Back from the pause menu (from LevelClear, the game is still up)
+                        draw_game(fb, screen, &players, &balls, &vaders, &capsules, lives);
+                        for shot in &shots { fill(fb, shot.rect, SHOT_COLOR); }
+                        if let Some((text, _)) = &banner { draw_banner(fb, screen, text); }
+                    }
+                    Scene::Paused => draw_menu(fb, screen, "PAUSED", &PAUSE_MENU, menu_selected),
+                    Scene::LevelClear => {
+                        sfx.play(&mut synth, SfxId::Clear, 0);
+                        if let Some((text, _)) = &banner { draw_banner(fb, screen, text); } // Erase
+                        let text = alloc::format!("LEVEL {} CLEAR", level_idx+1);
+                        draw_banner(fb, screen, &text);
+                        banner = Some((text, 0)); // Comes down with the next level
+                    }
+                    Scene::GameOver if demo => next_scene = Some(Scene::Title), // Demo's over
+                    Scene::GameOver => { // The bloop is playing; the game over menu comes up when it's done
+                        // Record high scores now, so they count whichever way the player leaves the menu
+                        new_high = None;
+                        if mode != GameMode::Versus {
+                            for player in &players {
+                                if let Some(rank) = settings.add_high_score(player.score) {
+                                    new_high = Some(rank); // In co-op, the last one in gets the highlight
                                 }
                             }
-                            video_frame_counter_last = Some(video_frame_counter);
-                            frame_already_overdue = false;
-                            missed_deadline_already = false;
+                            if new_high.is_some() && !save::store(&peripherals.APF_BRIDGE, &settings) {
+                                println!("Couldn't save high scores");
+                            }
                         }
                     }
+                    Scene::HighScores => {
+                        let highlight = if from == Scene::GameOver { new_high } else { None };
+                        draw_high_scores(fb, screen, &settings.high_scores, highlight, launch_name(&inputs));
+                    }
                 }
-
-                if frame_ready { break; }
+                profiler.switch(scope);
             }
 
-            // Controls
-
-            let cont1_key = peripherals.APF_INPUT.cont1_key.read().bits() as u16; // Bitmask (crop out analog sticks)
-            let cont1_key_edge = (!cont1_key_last) & cont1_key; // Bitmask is 1 iff a button press is *new this frame*
-            cont1_key_last = cont1_key;
+            if let Some(r) = &mut remap { // Controls: Remap screen (replaces all other controls while up)
+                let cont = inputs.player(r.port);
+                let button = cont.key_edge & cont.key_edge.wrapping_neg(); // Lowest newly pressed button, if any
+                let taken = r.bindings.buttons[..r.action].iter().any(|&b| b & button != 0); // Don't bind a button twice
+                if button != 0 && !taken {
+                    r.bindings.buttons[r.action] = button;
+                    r.action += 1;
+                    if r.action < ACTION_COUNT {
+                        let scope = profiler.switch(Scope::Draw);
+                        draw_remap(fb, screen, r);
+                        profiler.switch(scope);
+                    } else { // All actions bound
+                        let mut bindings = inputs.bindings();
+                        bindings[r.port] = r.bindings;
+                        inputs.set_bindings(bindings);
+                        settings.bindings = bindings;
+                        if !save::store(&peripherals.APF_BRIDGE, &settings) {
+                            println!("Couldn't save settings");
+                        }
+                        remap = None;
 
-            use PocketControls::*;
+                        // Back to the pause menu
+                        let scope = profiler.switch(Scope::Draw);
+                        draw_menu(fb, screen, "PAUSED", &PAUSE_MENU, menu_selected);
+                        profiler.switch(scope);
+                    }
+                }
+            } else if next_scene.is_none() { // Controls: Menus (any controller)
+                let (menu_step, menu_pick) = menu_input(&inputs);
+
+                // Cursor movement, for scenes with a menu up
+                let menu_len = match scene {
+                    Scene::ModeSelect => MODE_MENU.len(),
+                    Scene::Paused => PAUSE_MENU.len(),
+                    Scene::GameOver if !audio_blooping => GAME_OVER_MENU.len(),
+                    _ => 0,
+                };
+                if menu_step != 0 && menu_len > 0 {
+                    menu_selected = (menu_selected as i32 + menu_step).rem_euclid(menu_len as i32) as usize;
+                    let scope = profiler.switch(Scope::Draw);
+                    match scene {
+                        Scene::ModeSelect => draw_menu(fb, screen, "SELECT MODE", &MODE_MENU, menu_selected),
+                        Scene::Paused => draw_menu(fb, screen, "PAUSED", &PAUSE_MENU, menu_selected),
+                        _ => draw_game_over(fb, screen, won, &players, (mode != GameMode::Versus).then_some(level_idx), menu_selected),
+                    }
+                    profiler.switch(scope);
+                }
 
-            // Controls: Reset
-            if cont1_key_edge & FaceStart as u16 != 0 {
-                unsafe { peripherals.CTRL.reset.write(|w| w.bits(1)); } // 1 resets entire SOC
+                match scene {
+                    Scene::Boot | Scene::LevelClear => {}
+                    Scene::Title => {
+                        if menu_pick.is_some() {
+                            next_scene = Some(Scene::ModeSelect);
+                        } else if inputs.any_triggered(Menu).is_some() { // Reset
+                            next_scene = Some(Scene::Boot); // Soft restart
+                        } else if inputs.any_pressed() {
+                            scene_steps = 0; // Somebody's there; hold off the demo
+                        } else if scene_steps >= ATTRACT_WAIT {
+                            demo = true;
+                            (mode, cpu) = (GameMode::Single, None); // One paddle, and the computer has it
+                            restart = true;
+                            next_scene = Some(Scene::Playing);
+                        }
+                    }
+                    Scene::ModeSelect => {
+                        if menu_pick.is_some() {
+                            if let Some(&picked) = MODE_MENU_MODES.get(menu_selected) {
+                                mode_picked = picked;
+                                (mode, cpu) = picked;
+                                println!("Mode: {:?} {:?}", mode, cpu);
+                                restart = true;
+                                next_scene = Some(Scene::Playing);
+                            } else {
+                                next_scene = Some(Scene::HighScores);
+                            }
+                        } else if inputs.any_triggered(Pause).is_some() { // Back
+                            next_scene = Some(Scene::Title);
+                        }
+                    }
+                    Scene::Playing => {
+                        if demo {
+                            if inputs.any_pressed() { next_scene = Some(Scene::Title) }
+                        } else if inputs.any_triggered(Pause).is_some() || inputs.any_triggered(Menu).is_some() {
+                            next_scene = Some(Scene::Paused);
+                        }
+                    }
+                    Scene::Paused => {
+                        if inputs.any_triggered(Pause).is_some() {
+                            next_scene = Some(Scene::Playing);
+                        } else if let Some(port) = menu_pick {
+                            match menu_selected {
+                                0 => next_scene = Some(Scene::Playing),
+                                1 => {
+                                    restart = true;
+                                    next_scene = Some(Scene::Playing);
+                                }
+                                2 => { // Remap screen for the controller that asked
+                                    let r = Remap { port, action:0, bindings:inputs.player(port).bindings };
+                                    let scope = profiler.switch(Scope::Draw);
+                                    draw_remap(fb, screen, &r);
+                                    profiler.switch(scope);
+                                    remap = Some(r);
+                                }
+                                3 => next_scene = Some(Scene::Title),
+                                _ => { // Hard reset. Slower, and loses a binary uploaded over UART.
+                                    unsafe { peripherals.CTRL.reset.write(|w| w.bits(1)); } // 1 resets entire SOC
+                                }
+                            }
+                        }
+                    }
+                    Scene::GameOver => {
+                        if !audio_blooping && menu_pick.is_some() {
+                            if menu_selected == 0 {
+                                next_scene = Some(Scene::HighScores);
+                            } else {
+                                restart = true;
+                                next_scene = Some(Scene::Playing);
+                            }
+                        }
+                    }
+                    Scene::HighScores => {
+                        if menu_pick.is_some() {
+                            next_scene = Some(Scene::Title);
+                        }
+                    }
+                }
             }
 
-            // Controls: Pause
-            if !dead && cont1_key_edge & FaceSelect as u16 != 0 {
-                paused = !paused;
-            }
+            audio_service(&mut refill, &mut synth, &mut music, &peripherals.APF_AUDIO, &mut profiler); // Remap screen redraws are slow
 
             // Mechanics
 
-            if !paused && !dead && !won { // In these cases, freeze screen and loop to handle audio
-                // Vader mechanics
-                // (When one block is left, have it start moving so you aren't stuck unable to hit it.)
-                if vaders.len() == 1 {
-                    let vader = &mut vaders[0];
-                    fill(fb, vader.rect, VADER_COLOR); // Erase
+            profiler.switch(Scope::Physics);
+
+            // Run as many fixed-length logic steps as the time since last frame calls for (see timing.rs)
+            for _ in 0..sim_steps {
+                scene_steps += 1;
+                let edges = core::mem::take(&mut step_edges); // The first step gets the presses, later ones none
+                if scene != Scene::Playing || next_scene.is_some() { continue } // Everywhere else, freeze screen and loop to handle audio
+
+                // Level banner comes down when its time is up
+                if let Some((text, wait)) = &mut banner {
+                    *wait -= 1;
+                    if *wait <= 0 {
+                        draw_banner(fb, screen, text); // Erase
+                        banner = None;
+                    }
+                }
 
-                    if final_vader_facing == 0 { // This is our first frame with only 1 vader
+                // Powers wear off
+                let mut expired:Vec<Effect> = Vec::new();
+                effects.retain_mut(|effect| {
+                    effect.left -= 1;
+                    if effect.left <= 0 { expired.push(*effect) }
+                    effect.left > 0
+                });
+                for effect in expired {
+                    match effect.power {
+                        Power::Wide | Power::Narrow => resize_player(fb, screen, &mut players, effect.player, PLAYER_SIZE.x),
+                        Power::Sticky => for ball in &mut balls { // Let go of anything caught (it launches next step)
+                            if ball.aim.is_none() && ball.stuck.is_some_and(|(pidx, _)| pidx == effect.player) { ball.wait = 0 }
+                        }
+                        _ => {}
+                    }
+                }
+
+                // Vader mechanics (see Behavior)
+                formation.steps += 1;
+
+                // Last breakable vader breaks formation, if the level allows
+                let mut breakable = vaders.iter_mut().filter(|vader| vader.brick.breakable());
+                if let (Some(vader), None) = (breakable.next(), breakable.next()) {
+                    if level.runaway && !matches!(vader.behavior, Behavior::Runaway { .. }) {
                         // For an initial direction, move toward the screen center
-                        final_vader_facing = if vader.rect.center().x > DISPLAY_WIDTH as i32/2
-                            { -1 } else { 1 }
+                        let facing = if vader.rect.center().x > DISPLAY_WIDTH as i32/2 { -1 } else { 1 };
+                        vader.behavior = Behavior::Runaway { facing };
+                    }
+                }
+
+                // Formation marches a pixel sideways every so often, or comes down at a screen edge.
+                // (Patrols count for where they could go, not just where they are, so they stay on screen.)
+                if let Some(march) = level.march {
+                    if formation.steps % march.period == 0 {
+                        let reach = |vader:&Vader| match vader.behavior {
+                            Behavior::Patrol { path, .. } => {
+                                let ul = vader.home + formation.offset;
+                                IRect2::new(ul.min(ul + path), ul.max(ul + path) + vader.rect.size())
+                            }
+                            _ => vader.rect,
+                        };
+                        let marching = vaders.iter().filter(|vader| !matches!(vader.behavior, Behavior::Runaway { .. })).map(reach);
+                        let side = IVec2::new(formation.facing, 0);
+                        if marching.clone().all(|rect| screen.enclose(rect.offset(side))) {
+                            formation.offset += side;
+                        } else {
+                            formation.facing = -formation.facing;
+                            let bottom = marching.map(|rect| rect.br.y).max().unwrap_or(MARCH_FLOOR);
+                            formation.offset.y += march.drop.min(MARCH_FLOOR - bottom).max(0);
+                        }
+                    }
+                }
+
+                // Formation and patrolling vaders go where they belong now
+                for vader in &mut vaders {
+                    let ul = match &mut vader.behavior {
+                        Behavior::Formation => vader.home + formation.offset,
+                        Behavior::Patrol { path, period, at, dir } => {
+                            let length = path.x.abs().max(path.y.abs()).max(1);
+                            if formation.steps % *period == 0 {
+                                *at += *dir;
+                                if *at <= 0 || *at >= length { *dir = -*dir } // Turn around at either end
+                            }
+                            vader.home + formation.offset + *path * *at / length
+                        }
+                        Behavior::Runaway { .. } => continue, // Moves itself, below
+                    };
+                    if ul != vader.rect.ul {
+                        fill(fb, vader.rect, vader_color(vader.brick)); // Erase
+                        vader.rect = IRect2::new(ul, ul + vader.rect.size());
+                        fill(fb, vader.rect, vader_color(vader.brick)); // Draw
                     }
+                }
+
+                // Runaway moves side to side
+                let runaway = vaders.iter().enumerate()
+                    .find_map(|(idx, vader)| match vader.behavior { Behavior::Runaway { facing } => Some((idx, facing)), _ => None });
+                if let Some((idx, facing)) = runaway {
+                    let others = vaders.iter().enumerate().filter(|&(other, _)| other != idx).map(|(_, vader)| vader.rect);
+                    let blocked = |rect:IRect2| others.clone().any(|other| other.intersect(rect));
+                    let vader = &vaders[idx];
+                    fill(fb, vader.rect, vader_color(vader.brick)); // Erase
 
                     // Move vader per facing
-                    let vader_move = IVec2::new(final_vader_facing, 0);
+                    let vader_move = IVec2::new(facing, 0);
                     let rect = vader.rect.offset(vader_move);
 
-                    // Bounce at screen edge
-                    vader.rect = if screen.enclose(rect) { rect } else {
-                        final_vader_facing = -final_vader_facing;
-                        vader.rect.offset(-vader_move)
+                    // Bounce at screen edge, or off another vader
+                    let (rect, facing) = if screen.enclose(rect) && !blocked(rect) { (rect, facing) } else {
+                        (vader.rect.offset(-vader_move), -facing)
                     };
 
-                    fill(fb, vader.rect, VADER_COLOR); // Draw
+                    let vader = &mut vaders[idx];
+                    vader.rect = rect;
+                    vader.behavior = Behavior::Runaway { facing };
+                    fill(fb, vader.rect, vader_color(vader.brick)); // Draw
+                }
+
+                // Vaders fire down at the paddles, if the level says so: a random breakable vader every so often,
+                // more often as fewer are left
+                if let Some(period) = level.shoot {
+                    formation.shot_wait -= 1;
+                    if formation.shot_wait <= 0 {
+                        let left = vaders.iter().filter(|vader| vader.brick.breakable()).count();
+                        formation.shot_wait = (period * left as i32 / formation.size.max(1) as i32).max(SHOT_PERIOD_MIN);
+                        let shooter = vaders.iter().filter(|vader| vader.brick.breakable()).nth(rng.below(left as u32) as usize);
+                        if let (Some(vader), true) = (shooter, shots.len() < SHOT_MAX) {
+                            let rect = IRect2::new_centered(IVec2::new(vader.rect.center().x, vader.rect.br.y + SHOT_SIZE.y/2), SHOT_SIZE);
+                            let shot = Shot { rect, y:Fixed::from_int(rect.ul.y) };
+                            fill(fb, shot.rect, SHOT_COLOR); // Draw
+                            shots.push(shot);
+                            sfx.play(&mut synth, SfxId::Shoot, pan_position(vader.rect.center().x, screen.ul.x, screen.br.x));
+                        }
+                    }
+                }
+
+                // Computer players decide what to press
+                let ball_bounds = IRect2::new(screen.ul + BALL_SIZE/2, screen.br - BALL_SIZE/2);
+                for (pidx, player) in players.iter_mut().enumerate() {
+                    let face = paddle_face(player);
+                    if let Some(ai) = &mut player.ai {
+                        let flying = balls.iter().filter(|ball| ball.stuck.is_none()).map(|ball| (ball.rect.center(), ball.velocity));
+                        let holding = balls.iter().any(|ball| ball.stuck.is_some_and(|(held_by, _)| held_by == pidx));
+                        ai.update(player.rect, face, flying, holding, ball_bounds);
+                    }
                 }
 
                 // Player mechanics
-                for player in &mut players { // 2 player mode left as exercise to reader
+                for pidx in 0..players.len() {
+                    let player = &mut players[pidx];
+                    let cont = controller(&inputs, &edges, player);
+
                     // Controls: Movement
                     // Here we go to quite some trouble to handle the case of left and right held down at once--
-                    // Which is impossible on the Analogue builtin buttons. But maybe it could happen on bluetooth
-                    const LR_MASK:u16 = DpadLeft as u16 | DpadRight as u16;
-                    player.facing = if cont1_key & LR_MASK == LR_MASK { // L+R both down case:
-                        if cont1_key_edge & DpadLeft as u16 != 0 { -1 }
-                        else if cont1_key_edge & DpadRight as u16 != 0 { 1 }
+                    // Which is impossible on the Analogue builtin d-pad. But it can happen with remapped buttons
+                    player.facing = if cont.held(MoveLeft) && cont.held(MoveRight) { // L+R both down case:
+                        if cont.triggered(MoveLeft) { -1 }
+                        else if cont.triggered(MoveRight) { 1 }
                         else { player.facing }
                     } else { // Only one of L+R down case:
-                        if cont1_key & DpadLeft as u16 != 0 { -1 }
-                        else if cont1_key & DpadRight as u16 != 0 { 1 }
+                        if cont.held(MoveLeft) { -1 }
+                        else if cont.held(MoveRight) { 1 }
                         else { 0 }
                     };
 
-                    if player.facing != 0 { // If moving
-                        if CONFIG_CHAOS < 2 {
-                            fill(fb, player.rect, PLAYER_COLOR); // Erase
-                        }
+                    // Controls: Analog movement
+                    // D-pad wins if held; otherwise the stick moves the paddle proportional to tilt.
+                    let speed = if player.facing == 0 && cont.stick_left.x != 0 {
+                        let x = cont.stick_left.x;
+                        player.facing = x.signum();
+                        Fixed::from_ratio(x * PLAYER_SPEED_ANALOG_MAX, ANALOG_MAX)
+                    } else {
+                        PLAYER_SPEED * player.facing
+                    };
 
-                        // Update based on facing, then force back inside screen.
-                        player.rect = player.rect.offset(IVec2::new(player.facing*PLAYER_SPEED, 0))
+                    if speed != Fixed::ZERO { // If moving
+                        // Update based on velocity, then force back inside screen.
+                        let x = player.x + speed;
+                        let rect = player.rect.offset(IVec2::new(x.round() - player.rect.ul.x, 0))
                             .force_enclose_x(screen);
+                        let x = if rect.ul.x == x.round() { x } else { Fixed::from_int(rect.ul.x) }; // Stopped at edge
+
+                        // In co-op, paddles can't pass through each other. Only moves that dig further in are
+                        // blocked, so paddles already overlapping (a Wide with no room to shift) can back apart.
+                        let overlap = |a:IRect2, b:IRect2| if a.intersect(b) { a.br.x.min(b.br.x) - a.ul.x.max(b.ul.x) } else { 0 };
+                        let blocked = players.iter().enumerate()
+                            .any(|(other_idx, other)| other_idx != pidx && overlap(rect, other.rect) > overlap(players[pidx].rect, other.rect));
+
+                        if !blocked {
+                            let player = &mut players[pidx];
+                            player.x = x;
+                            if rect.ul != player.rect.ul { // Only redraw when it moved a whole pixel
+                                if CONFIG_CHAOS < 2 {
+                                    fill(fb, player.rect, PLAYER_COLOR); // Erase
+                                }
+
+                                player.rect = rect;
 
-                        fill(fb, player.rect, PLAYER_COLOR); // Draw
+                                fill(fb, player.rect, PLAYER_COLOR); // Draw
+                            }
+                        }
                     }
                 }
 
-                // Ball
-                for ball in &mut balls {
-                    if CONFIG_CHAOS < 1 {
-                        fill(fb, ball.rect, BALL_COLOR); // Erase
+                audio_service(&mut refill, &mut synth, &mut music, &peripherals.APF_AUDIO, &mut profiler);
+
+                // Capsules fall until a paddle catches them or they leave the screen
+                let mut caught:Vec<(Power, usize)> = Vec::new(); // Power and player
+                capsules.retain_mut(|capsule| {
+                    draw_capsule(fb, capsule); // Erase
+                    capsule.y += CAPSULE_SPEED;
+                    capsule.rect = capsule.rect.offset(IVec2::new(0, capsule.y.round() - capsule.rect.ul.y));
+                    if let Some(pidx) = players.iter().position(|player| player.rect.intersect(capsule.rect)) {
+                        caught.push((capsule.power, pidx));
+                        return false;
+                    }
+                    if !screen.enclose(capsule.rect) { return false }
+                    draw_capsule(fb, capsule); // Draw
+                    true
+                });
+
+                for (power, pidx) in caught {
+                    sfx.play(&mut synth, SfxId::Power, pan_position(players[pidx].rect.center().x, screen.ul.x, screen.br.x));
+                    match power {
+                        Power::Multiball => {
+                            let source = balls.iter().find(|ball| ball.stuck.is_none()).or(balls.first());
+                            let split:Vec<Ball> = source.into_iter().flat_map(|source| {
+                                [-POWER_MULTIBALL_ANGLE, POWER_MULTIBALL_ANGLE].map(|angle| {
+                                    let out = FVec2::from_angle(angle, source.velocity.length());
+                                    let velocity = FVec2::new(out.x, out.y.with_sign_of(source.velocity.y));
+                                    Ball { rect:source.rect, pos:source.pos, velocity, owner:source.owner, wait:0, stuck:None, aim:None, combo:0 }
+                                })
+                            }).take(BALL_MAX.saturating_sub(balls.len())).collect();
+                            for ball in split {
+                                fill(fb, ball.rect, BALL_COLOR); // Draw
+                                balls.push(ball);
+                            }
+                        }
+                        Power::Life => {
+                            draw_lives(fb, screen, lives); // Erase
+                            lives = (lives + 1).min(LIVES_MAX);
+                            draw_lives(fb, screen, lives); // Draw
+                        }
+                        _ => { // Timed. Catching one again restarts its clock.
+                            let replaces = |other:Power| match power {
+                                Power::Wide | Power::Narrow => other == Power::Wide || other == Power::Narrow,
+                                _ => other == power,
+                            };
+                            effects.retain(|effect| !(replaces(effect.power) && (effect.player == pidx || power == Power::Slow)));
+                            effects.push(Effect { power, player:pidx, left:POWER_TIME });
+                            match power {
+                                Power::Wide => resize_player(fb, screen, &mut players, pidx, POWER_WIDE),
+                                Power::Narrow => resize_player(fb, screen, &mut players, pidx, POWER_NARROW),
+                                _ => {}
+                            }
+                        }
                     }
+                }
 
-                    // Step one pixel at a time, one axis at a time.
-                    'step: for _ in 0..BALL_SPEED {
-                        for (aid, axis) in IVec2::AXES.into_iter().enumerate() {
-                            let v = axis*ball.facing; // Velocity on this axis
+                // Shots fall until they hit a paddle, which costs a life, or leave the screen
+                let mut hit:Option<usize> = None; // Player hit
+                shots.retain_mut(|shot| {
+                    fill(fb, shot.rect, SHOT_COLOR); // Erase
+                    shot.y += SHOT_SPEED;
+                    shot.rect = shot.rect.offset(IVec2::new(0, shot.y.round() - shot.rect.ul.y));
+                    if let Some(pidx) = players.iter().position(|player| player.rect.intersect(shot.rect)) {
+                        hit = Some(pidx);
+                        return false;
+                    }
+                    if !screen.enclose(shot.rect) { return false }
+                    fill(fb, shot.rect, SHOT_COLOR); // Draw
+                    true
+                });
+                if let Some(pidx) = hit {
+                    sfx.play(&mut synth, SfxId::Hurt, pan_position(players[pidx].rect.center().x, screen.ul.x, screen.br.x));
+                    if lives > 0 {
+                        draw_lives(fb, screen, lives); // Erase
+                        lives -= 1;
+                        draw_lives(fb, screen, lives); // Draw
+                    } else if !CONFIG_IMMORTAL { // Shot down with no lives left. Game over.
+                        won = false;
+                        next_scene = Some(Scene::GameOver);
+                        play_bloop(&mut synth, &mut music, &mut sfx, won);
+                        audio_blooping = true;
+                        continue;
+                    }
+                }
 
-                            //println!("TEST!! {}: {:?} = {:?} * {:?}", step, (aid,axis), ball.facing, v);
+                // Ball
+                let mut lost:Vec<usize> = Vec::new(); // Balls that fell off the bottom without ending the game
+                let mut serve:Option<usize> = None; // Set when the last ball fell off and a life pays for a new one: who serves it
+                let ball_count = balls.len();
+                let speed_scale = if effects.iter().any(|effect| effect.power == Power::Slow) { POWER_SLOW } else { Fixed::ONE };
+                for (bidx, ball) in balls.iter_mut().enumerate() {
+                    if let Some((pidx, offset)) = ball.stuck { // Riding a paddle
+                        let player = &players[pidx];
+                        draw_ball(fb, ball, &players); // Erase
+                        ball.rect = held_rect(player, offset);
+                        ball.pos = FVec2::from_ivec2(ball.rect.ul);
+                        let launch = controller(&inputs, &edges, player).triggered(Launch);
+                        if let Some(aim) = &mut ball.aim { // Serving
+                            if launch { // Off exactly where the indicator points (no "english", it isn't drawn)
+                                let out = FVec2::from_angle(serve_angle(*aim), serve_speed);
+                                ball.velocity = FVec2::new(out.x, out.y.with_sign_of(Fixed::from_int(paddle_face(player))));
+                                ball.stuck = None;
+                                ball.aim = None;
+                            } else {
+                                *aim += 1;
+                            }
+                        } else { // Caught by a sticky paddle
+                            ball.wait -= 1;
+                            if ball.wait <= 0 || launch {
+                                ball.stuck = None; // Off it goes, at the angle it bounced at
+                                ball.wait = 0;
+                            }
+                        }
+                        draw_ball(fb, ball, &players); // Draw
+                        continue;
+                    }
 
-                            let rect = ball.rect.offset(v); // Candidate rectangle, if we move 1 pixel on that axis.
-                            let mut reflect = false; // Code below will test for a collision, and set "reflect" to reject the new position.
+                    if CONFIG_CHAOS < 1 {
+                        fill(fb, ball.rect, BALL_COLOR); // Erase
+                    }
 
-                            if !screen.enclose(rect) { // Test collision with edge of screen
-                                if v.y<=0 || CONFIG_IMMORTAL {
-                                    reflect = true;
-                                    audio_bleep_high = false;
-                                } else {
-                                    // Touched bottom of screen. Game over.
-                                    dead = true;
-                                    audio_blooping = AUDIO_DEATH_BLOOP;
-                                    break 'step;
+                    // Step at most one pixel at a time, one axis at a time. A paddle re-angling the ball or a
+                    // speedup can make it faster on an axis, so then the substeps are sized again for the rest.
+                    let mut left = Fixed::ONE; // Fraction of this step's movement still to go
+                    'step: while left > Fixed::ZERO {
+                        let start = left;
+                        let velocity = ball.velocity * speed_scale * start;
+                        let substeps = velocity.x.abs().max(velocity.y.abs()).ceil().max(1);
+                        left = Fixed::ZERO;
+                        for sub in 0..substeps {
+                            for aid in 0..2 {
+                                let speed = (ball.velocity.x.abs(), ball.velocity.y.abs());
+                                let pos = ball.pos + (ball.velocity * speed_scale * start / substeps).only_axis(aid); // Candidate position
+                                let rect = IRect2::new(pos.round(), pos.round() + BALL_SIZE); // Candidate rectangle
+                                let v = rect.ul - ball.rect.ul; // Pixel step on this axis (0 or +-1)
+                                if v == IVec2::ZERO { ball.pos = pos; continue } // Same pixel; nothing new to hit
+
+                                let mut reflect = false; // Code below will test for a collision, and set "reflect" to reject the new position.
+
+                                if !screen.enclose(rect) { // Test collision with edge of screen
+                                    if mode == GameMode::Versus && v.y != 0 {
+                                        // Touched top or bottom of screen. Point to whoever's goal that wasn't.
+                                        let scorer = if v.y > 0 { 1 } else { 0 };
+                                        let player = &mut players[scorer];
+                                        draw_score(fb, screen, scorer, player.score); // Erase
+                                        player.score += 1;
+                                        draw_score(fb, screen, scorer, player.score); // Draw
+
+                                        if player.score >= VERSUS_WIN_SCORE {
+                                            won = true;
+                                            play_bloop(&mut synth, &mut music, &mut sfx, won);
+                                            audio_blooping = true;
+                                            next_scene = Some(Scene::GameOver);
+                                            font::text_centered(fb, screen, screen.center().y, TEXT_SCALE, TEXT_COLOR,
+                                                &alloc::format!("P{} WINS", scorer+1));
+                                        }
+
+                                        // The player who lost the point serves
+                                        *ball = serve_ball(&players, 1 - scorer);
+                                        break 'step;
+                                    } else if v.y<=0 || CONFIG_IMMORTAL {
+                                        reflect = true;
+                                        audio_bleep = SfxId::Bounce;
+                                        audio_bleep_pan = pan_position(ball.rect.center().x, screen.ul.x, screen.br.x);
+                                    } else if ball_count - lost.len() > 1 {
+                                        // Touched bottom of screen, but another ball is still in play. Just lose this one.
+                                        lost.push(bidx);
+                                        break 'step;
+                                    } else if lives > 0 {
+                                        // Touched bottom of screen with the last ball. Spend a life to serve another.
+                                        lost.push(bidx);
+                                        serve = Some(ball.owner);
+                                        break 'step;
+                                    } else {
+                                        // Touched bottom of screen. Game over.
+                                        won = false;
+                                        next_scene = Some(Scene::GameOver);
+                                        play_bloop(&mut synth, &mut music, &mut sfx, won);
+                                        audio_blooping = true;
+                                        break 'step;
+                                    }
                                 }
-                            }
 
-                            for player in &players {
-                                if reflect { break; } // Already rejected
-
-                                // Test collision with player paddle
-                                if player.rect.intersect(rect) {
-                                    reflect = true;
-                                    audio_bleep_high = false;
+                                let mut stick:Option<usize> = None; // Set when a sticky paddle catches the ball
+                                for (pidx, player) in players.iter().enumerate() {
+                                    if reflect { break; } // Already rejected
+
+                                    // Test collision with player paddle
+                                    if player.rect.intersect(rect) {
+                                        reflect = true;
+                                        audio_bleep = SfxId::Bounce;
+                                        audio_bleep_pan = pan_position(ball.rect.center().x, screen.ul.x, screen.br.x);
+                                        ball.owner = pidx;
+                                        ball.combo = 0;
+
+                                        // "Steer" based on where on the paddle you hit: the outgoing angle goes from straight
+                                        // back at the center to BALL_ANGLE_MAX at the edges, plus "english" from paddle motion.
+                                        // (Only when hitting the paddle's face; the versus top paddle is hit moving up)
+                                        if v.y != 0 {
+                                            let offset = ball.rect.center().x - player.rect.center().x;
+                                            let angle = (offset * BALL_ANGLE_MAX / (player.rect.size().x/2) + player.facing * BALL_ANGLE_ENGLISH)
+                                                .clamp(-BALL_ANGLE_MAX, BALL_ANGLE_MAX);
+                                            // Keep y heading into the paddle; the reflect below turns it around
+                                            let out = FVec2::from_angle(angle, ball.velocity.length());
+                                            ball.velocity = FVec2::new(out.x, out.y.with_sign_of(ball.velocity.y));
+
+                                            if effects.iter().any(|effect| effect.power == Power::Sticky && effect.player == pidx) {
+                                                stick = Some(pidx);
+                                            }
+                                        }
+                                    }
+                                }
 
-                                    // "Steer" based on where on the paddle you hit
-                                    if v.y>0 {
-                                        ball.facing.x = if ball.rect.center().x > player.rect.center().x
-                                            { 1 } else { -1 }
+                                let mut destroy:Option<usize> = None; // Select a vader to destroy
+                                for (idx,vader) in vaders.iter_mut().enumerate() {
+                                    if reflect { break; } // Already rejected
+
+                                    if vader.rect.intersect(rect) {
+                                        reflect = true;
+                                        audio_bleep_pan = pan_position(vader.col, 0, level.cols-1);
+                                        match vader.brick {
+                                            Brick::Steel => audio_bleep = SfxId::Bounce, // Just a wall
+                                            Brick::Normal(hits) if hits > 1 => { // Wear it down
+                                                audio_bleep = SfxId::Hit;
+                                                fill(fb, vader.rect, vader_color(vader.brick)); // Erase
+                                                vader.brick = Brick::Normal(hits-1);
+                                                fill(fb, vader.rect, vader_color(vader.brick)); // Draw
+                                            }
+                                            brick => {
+                                                audio_bleep = if brick == Brick::Bomb { SfxId::Explode } else { SfxId::Hit }; // Only vaders bleep high
+                                                destroy = Some(idx);
+                                            }
+                                        }
                                     }
                                 }
-                            }
 
-                            let mut destroy:Option<usize> = None; // Select a vader to destroy
-                            for (idx,vader) in vaders.iter().enumerate() {
-                                if reflect { break; } // Already rejected
+                                // Did we select a vader to destroy?
+                                // (We have to do this afterward so we don't mutate the vec while iterating it.)
+                                if let Some(idx) = destroy {
+                                    let gone = destroy_vader(&mut vaders, idx); // Erase vader (object)
+                                    for vader in &gone { fill(fb, vader.rect, vader_color(vader.brick)); } // Erase vader (screen)
+
+                                    // Maybe drop a capsule where it was hit (not when that clears the level)
+                                    if rng.chance(CAPSULE_CHANCE.0, CAPSULE_CHANCE.1) && vaders.iter().any(|vader| vader.brick.breakable()) {
+                                        let power = Power::ALL[rng.below(Power::ALL.len() as u32) as usize];
+                                        let rect = IRect2::new_centered(gone[0].rect.center(), CAPSULE_SIZE);
+                                        let capsule = Capsule { rect, y:Fixed::from_int(rect.ul.y), power };
+                                        draw_capsule(fb, &capsule); // Draw
+                                        capsules.push(capsule);
+                                    }
 
-                                if vader.rect.intersect(rect) {
-                                    reflect = true;
-                                    destroy = Some(idx);
-                                    audio_bleep_high = true; // Only vaders bleep high
-                                    fill(fb, vader.rect, VADER_COLOR); // Erase vader (screen)
+                                    ball.combo += 1;
+                                    let player = &mut players[ball.owner];
+                                    draw_score(fb, screen, ball.owner, player.score); // Erase
+                                    player.score += gone.iter().map(|vader| vader.points).sum::<u32>() * ball.combo.min(COMBO_MAX);
+                                    draw_score(fb, screen, ball.owner, player.score); // Draw
+
+                                    // Speed up a little as the field clears
+                                    let speed = (ball.velocity.length() * BALL_SPEEDUP).min(BALL_SPEED_MAX);
+                                    ball.velocity = ball.velocity.with_length(speed);
+
+                                    if !vaders.iter().any(|vader| vader.brick.breakable()) { // Oh, that was the last (breakable) vader
+                                        if level_idx + 1 < level::count() {
+                                            next_scene = Some(Scene::LevelClear);
+                                        } else {
+                                            won = true;
+                                            play_bloop(&mut synth, &mut music, &mut sfx, won);
+                                            audio_blooping = true;
+                                            next_scene = Some(Scene::GameOver);
+                                        }
+                                        break 'step; // Don't bother drawing new ball position
+                                    }
                                 }
-                            }
 
-                            // Did we select a vader to destroy?
-                            // (We have to do this afterward so we don't mutate the vec while iterating it.)
-                            if let Some(idx) = destroy {
-                                vaders.remove(idx); // Erase vader (object)
-                                if vaders.len() == 0 { // Oh, that was the last vader
-                                    won = true;
-                                    audio_blooping = AUDIO_DEATH_BLOOP;
-                                    break 'step; // Don't bother drawing new ball position
+                                // Set ball position from candidate
+                                if !reflect {
+                                    ball.pos = pos;
+                                    ball.rect = rect;
+                                } else { // Candidate was rejected; set a rectangle in the opposite direction.
+                                    ball.velocity = ball.velocity.reflect_axis(aid);
+                                    sfx.play(&mut synth, audio_bleep, audio_bleep_pan);
+                                    ball.rect = ball.rect.offset(-v);
+                                    ball.pos = FVec2::from_ivec2(ball.rect.ul);
+
+                                    if let Some(pidx) = stick {
+                                        ball.stuck = Some((pidx, ball.rect.ul.x - players[pidx].rect.ul.x));
+                                        ball.wait = POWER_STICKY_HOLD;
+                                        break 'step;
+                                    }
+
+                                    if (ball.velocity.x.abs(), ball.velocity.y.abs()) != speed { // More than a reflect
+                                        left = start * (substeps - sub - 1) / substeps;
+                                        continue 'step;
+                                    }
                                 }
                             }
-
-                            // Set ball position from candidate
-                            ball.rect = if !reflect {
-                                rect
-                            } else { // Candidate was rejected; set a rectangle in the opposite direction.
-                                ball.facing *= REFLECTS[aid];
-                                audio_bleeping = AUDIO_REFLECT_BLEEP;
-                                ball.rect.offset(-v)
-                            };
                         }
                     }
 
-                    fill(fb, ball.rect, BALL_COLOR); // Draw
+                    if lost.last() == Some(&bidx) { continue } // Fell off; stays erased
+                    draw_ball(fb, ball, &players); // Draw
+                }
+                for &bidx in lost.iter().rev() { balls.remove(bidx); }
+
+                // Lost a life: whoever last touched the ball serves a fresh one
+                if let Some(pidx) = serve {
+                    draw_lives(fb, screen, lives); // Erase
+                    lives -= 1;
+                    draw_lives(fb, screen, lives); // Draw
+                    sfx.play(&mut synth, SfxId::Miss, 0);
+
+                    let ball = serve_ball(&players, pidx);
+                    draw_ball(fb, &ball, &players); // Draw
+                    balls.push(ball);
                 }
             }
 
-            #[cfg(feature = "speed-debug")]
-            {
-                let video = peripherals.APF_VIDEO.video.read();
-                if !video.vblank_status().bit() { // Status has already gone low
-                    if 0== missed_deadline_count % SPEED_DEBUG_RATE {
-                        println!("Too slow! Drawing finished outside vblank deadline (fail #{})", missed_deadline_count);
+            // Level clear: after a moment, the next level. New board, and a fresh ball on player 1's paddle while the level name shows.
+            // Scores carry over.
+            if scene == Scene::LevelClear && scene_steps >= LEVEL_CLEAR_WAIT && next_scene.is_none() {
+                level_idx += 1;
+                level = level::get(level_idx).unwrap();
+
+                for ball in &balls { draw_ball(fb, ball, &players); } // Erase
+                balls.clear();
+                let ball = serve_ball(&players, 0);
+                draw_ball(fb, &ball, &players); // Draw
+                balls.push(ball);
+
+                for vader in &vaders { fill(fb, vader.rect, vader_color(vader.brick)); } // Erase what's left (steel)
+                load_level(&mut vaders, &mut formation, &level);
+                for vader in &vaders { fill(fb, vader.rect, vader_color(vader.brick)); } // Draw
+
+                // Powers don't carry over (spare lives do)
+                for capsule in &capsules { draw_capsule(fb, capsule); } // Erase
+                capsules.clear();
+                for shot in &shots { fill(fb, shot.rect, SHOT_COLOR); } // Erase
+                shots.clear();
+                for effect in effects.drain(..) {
+                    if matches!(effect.power, Power::Wide | Power::Narrow) {
+                        resize_player(fb, screen, &mut players, effect.player, PLAYER_SIZE.x);
                     }
-                    missed_deadline_already = true;
                 }
-                frame_already_overdue = video.vblank_triggered().bit();
-            }
 
-            // Audio generation
+                if let Some((text, _)) = &banner { draw_banner(fb, screen, text); } // Erase
+                let text = level_banner(level_idx, &level);
+                draw_banner(fb, screen, &text);
+                banner = Some((text, BANNER_WAIT));
+                next_scene = Some(Scene::Playing);
+            }
 
-            // Generate enough samples to fill us up to our desired buffer (a frame plus a safety margin)
-            let audio_needed = AUDIO_TARGET - peripherals.APF_AUDIO.buffer_fill.read().bits() as i32;
-            for _ in 0..audio_needed {
-                let mut lfo_engaged = false; // True if the background "low pitched hum" is playing
-
-                if (!paused) { // When we pause we still output audio, held at the last PCM value.
-                    // Remember (see "On audio" above), we have one saw wave generator and an LFO.
-                    // freq_delta will determine the frequency of the saw generator this sample
-                    let freq_delta = if audio_blooping>0 { // Case 3, strobing bloop
-                        audio_blooping -= 1;
-
-                        // No matter what, the game ends when the bloop is done
-                        if audio_blooping == 0 { paused = true; }
-
-                        // The bloop silences itself 1/2 the time, on the STROBE boundary
-                        if 0!=(audio_blooping/AUDIO_DEATH_BLOOP_STROBE)%2 {
-                            // Run at base - 1 octave when losing, or base + 2 octaves when winning
-                            if !won { AUDIO_FREQ_DELTA/2 } else { AUDIO_FREQ_DELTA*4 }
-                        } else { 0 }
-                    } else if audio_bleeping>0 { // Case 2, single bleep
-                        audio_bleeping -= 1;
-
-                        // Run at base + 2 octaves for a vader, or base + 1 octave otherwise
-                        if audio_bleep_high { AUDIO_FREQ_DELTA*4 } else { AUDIO_FREQ_DELTA*2 }
-                    } else { // Case 1, pulsating hum at exactly base frequency
-                        lfo_engaged = true;
-                        AUDIO_FREQ_DELTA
-                    };
+            frame_sync.end_frame(&peripherals.APF_VIDEO);
 
-                    // Simplest waveform possible: Increment last sample's value by the delta, then wrap around at 2^16
-                    audio_wave = audio_wave.wrapping_add(freq_delta);
+            // Audio generation
 
-                    // LFO state only increments when not paused
-                    audio_lfo = (audio_lfo+1)%AUDIO_LFO_MAX;
-                }
+            // Keep the buffer topped up to our desired level (a frame plus a safety margin); see audio_service()
+            // When we pause we still output audio, held at the last PCM value.
+            // (Once the game is over the screen is frozen, but audio keeps going for the victory music.)
+            synth.paused = scene == Scene::Paused;
+            audio_service(&mut refill, &mut synth, &mut music, &peripherals.APF_AUDIO, &mut profiler);
 
-                let mut value:u32 = audio_wave as u32;
+            // When the bloop is done, the game over menu comes up
+            if audio_blooping && !sfx.playing(&synth, if won { SfxId::Win } else { SfxId::Lose }) {
+                audio_blooping = false;
+                if won { music.play(&mut synth, music::VICTORY); }
 
-                // Max volume is 2^12-1
-                value = value >> 4;
+                let scope = profiler.switch(Scope::Draw);
+                draw_game_over(fb, screen, won, &players, (mode != GameMode::Versus).then_some(level_idx), menu_selected);
+                profiler.switch(scope);
+            }
 
-                // Apply LFO envelope
-                if lfo_engaged { value *= audio_lfo as u32; value /= AUDIO_LFO_MAX as u32; }
+            unsafe { peripherals.APF_AUDIO.playback_en.write(|w| w.bits(1)) };
 
-                // Output value is two stereo i16s packed into one u32
-                // Notice we did our math above in u32; it doesn't matter because bit 15 is always 0
-                value = value | (value << 16);
+            profiler.switch(Scope::Idle); // Until next vblank
 
-                unsafe { peripherals.APF_AUDIO.out.write(|w| w.bits(value)) };
+            // Controls: L1+R1 together prints the profile over UART (speed-debug only)
+            let dump = (0..CONTROLLER_COUNT).any(|port| {
+                let cont = inputs.player(port);
+                cont.down(PocketControls::TrigL1) && cont.down(PocketControls::TrigR1)
+                    && (cont.pressed(PocketControls::TrigL1) || cont.pressed(PocketControls::TrigR1))
+            });
+            if profile::ENABLED && dump {
+                profiler.dump(frame_sync.missed, refill.underruns);
             }
 
-            unsafe { peripherals.APF_AUDIO.playback_en.write(|w| w.bits(1)) };
-
             // Uncomment if you need to know if you're on the first frame
-            // if (!paused) {
+            // if scene == Scene::Playing {
             //     first_frame = false;
             // }
         }
@@ -534,3 +1598,37 @@ fn main() -> ! {
 
     // Unreachable
 }
+
+// Unit tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use irect2::IRect2;
+
+    #[test]
+    fn level_transition() { // Steel left when a level is cleared has to be erased, or it XORs into the next board
+        const STEEL:u16 = 0x8410;
+        const BRICK:u16 = 0x07E0;
+        // Where a level's steel (or other) bricks start out
+        fn rects(level:&level::Level, steel:bool) -> impl Iterator<Item=IRect2> + '_ {
+            level.bricks().filter(move |&(_, _, brick)| (brick == level::Brick::Steel) == steel)
+                .map(|(col, row, _)| { let ul = level.brick_ul(col, row, DISPLAY_WIDTH as i32); IRect2::new(ul, ul + level.brick) })
+        }
+        let cleared = (0..level::count()).filter_map(level::get).find(|level| level.bricks().any(|(_, _, brick)| brick == level::Brick::Steel))
+            .expect("Some level should have steel");
+        let next = level::get(0).unwrap();
+
+        let mut clean = alloc::vec![0u16; READ_LENGTH];
+        for rect in rects(&next, false) { fill(clean.as_mut_ptr(), rect, BRICK); }
+
+        let mut screen = alloc::vec![0u16; READ_LENGTH];
+        let fb = screen.as_mut_ptr();
+        for rect in rects(&cleared, true) { fill(fb, rect, STEEL); } // Left over
+        let mut skipped = screen.clone();
+        for rect in rects(&cleared, true) { fill(fb, rect, STEEL); } // Erase
+        for rect in rects(&next, false) { fill(fb, rect, BRICK); fill(skipped.as_mut_ptr(), rect, BRICK); } // Draw
+        assert!(screen == clean, "Next board should be drawn on a clean screen");
+        assert!(skipped != clean, "Without the erase, steel should still show");
+    }
+}
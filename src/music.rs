@@ -0,0 +1,198 @@
+// Tracker-style music player. Songs are converted from text at build time (see tracker.rs for
+// the text format, songformat.rs for the binary one) and played on a range of synth voices, one
+// voice per channel.
+// Notes:
+//    The player advances once per sample from Synth::fill, so rows land exactly on time no matter
+//    how the frame loop is doing.
+//    Song data is trusted (it was validated by the converter), but a bad version byte is refused.
+
+use crate::synth::*;
+use crate::songformat::*;
+pub use crate::songformat::MAX_CHANNELS;
+
+pub const TITLE:&[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/title.mbt"));
+pub const GAME:&[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/game.mbt"));
+pub const VICTORY:&[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/victory.mbt"));
+
+// Phase steps for MIDI notes 120-131 (C9-B9); lower octaves shift these right
+const TOP_OCTAVE:[u32; 12] = [749115498, 793660223, 840853716, 890853480, 943826385, 999949222,
+    1059409297, 1122405052, 1189146729, 1259857073, 1334772074, 1414141751];
+
+pub fn note_freq(note:u8) -> u32 {
+    let note = note.min(131) as usize;
+    TOP_OCTAVE[note % 12] >> (10 - note/12)
+}
+
+// View of a binary song
+#[derive(Clone, Copy)]
+struct Song {
+    data: &'static [u8],
+    samples_per_row: u32,
+    channels: usize,
+    instrument_count: usize,
+    rows: usize,
+    order_len: usize,
+    loop_to: u8,
+}
+
+impl Song {
+    fn new(data:&'static [u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE || data[0] != VERSION { return None }
+        Some(Song {
+            data,
+            samples_per_row: u16::from_le_bytes([data[1], data[2]]) as u32,
+            channels: (data[3] as usize).min(MAX_CHANNELS),
+            instrument_count: data[4] as usize,
+            // data[5] is the pattern count, which playback doesn't need
+            rows: data[6] as usize,
+            order_len: data[7] as usize,
+            loop_to: data[8],
+        })
+    }
+
+    fn order_start(&self) -> usize { HEADER_SIZE + self.instrument_count*INSTRUMENT_SIZE }
+    fn patterns_start(&self) -> usize { self.order_start() + self.order_len }
+
+    // Configure voice to play instrument
+    fn apply_instrument(&self, idx:u8, voice:&mut Voice) {
+        if idx as usize >= self.instrument_count { return }
+        let at = HEADER_SIZE + idx as usize*INSTRUMENT_SIZE;
+        let d = &self.data[at..at+INSTRUMENT_SIZE];
+        voice.waveform = match d[0] { 1 => Waveform::Square, 2 => Waveform::Triangle, 3 => Waveform::Noise, _ => Waveform::Saw };
+        voice.duty = (d[1] as u32) << 24;
+        voice.volume = d[2] as u16;
+        voice.adsr = Adsr {
+            sustain: d[3] as u32 * ENV_MAX / 255,
+            attack: u16::from_le_bytes([d[4], d[5]]) as u32,
+            decay: u16::from_le_bytes([d[6], d[7]]) as u32,
+            release: u16::from_le_bytes([d[8], d[9]]) as u32,
+        };
+        voice.tremolo = Tremolo::None;
+    }
+
+    // (note, instrument) for a channel at a position in the song
+    fn cell(&self, order:usize, row:usize, channel:usize) -> (u8, u8) {
+        let pattern = self.data[self.order_start() + order] as usize;
+        let at = self.patterns_start() + ((pattern*self.rows + row)*self.channels + channel)*2;
+        (self.data[at], self.data[at+1])
+    }
+}
+
+pub struct Music {
+    song: Option<Song>,
+    first_voice: usize, // Channel n plays on voice first_voice+n
+    order: usize,       // Position in song
+    row: usize,
+    countdown: u32,     // Samples until next row
+    instruments: [u8; MAX_CHANNELS], // Last instrument used on each channel
+}
+
+impl Music {
+    pub fn new(first_voice:usize) -> Self {
+        assert!(first_voice + MAX_CHANNELS <= VOICE_COUNT, "Not enough voices for music");
+        Music { song:None, first_voice, order:0, row:0, countdown:0, instruments:[0; MAX_CHANNELS] }
+    }
+
+    // Start a song from the beginning, replacing whatever was playing
+    pub fn play(&mut self, synth:&mut Synth, data:&'static [u8]) {
+        self.stop(synth);
+        self.song = Song::new(data);
+        self.order = 0;
+        self.row = 0;
+        self.countdown = 0;
+        self.instruments = [0; MAX_CHANNELS];
+    }
+
+    // Release any playing notes and stop
+    pub fn stop(&mut self, synth:&mut Synth) {
+        if let Some(song) = self.song.take() {
+            for channel in 0..song.channels {
+                synth.voices[self.first_voice + channel].note_off();
+            }
+        }
+    }
+
+    // Play the row at the current position, then move to the next
+    fn step_row(&mut self, synth:&mut Synth, song:Song) {
+        for channel in 0..song.channels {
+            let (note, instrument) = song.cell(self.order, self.row, channel);
+            let voice = &mut synth.voices[self.first_voice + channel];
+            if instrument != INSTRUMENT_KEEP { self.instruments[channel] = instrument; }
+            match note {
+                NOTE_NONE => {}
+                NOTE_OFF => voice.note_off(),
+                _ => {
+                    song.apply_instrument(self.instruments[channel], voice);
+                    voice.freq = note_freq(note);
+                    voice.note_on();
+                }
+            }
+        }
+
+        self.row += 1;
+        if self.row >= song.rows {
+            self.row = 0;
+            self.order += 1;
+            if self.order >= song.order_len {
+                if song.loop_to == NO_LOOP {
+                    self.stop(synth);
+                } else {
+                    self.order = song.loop_to as usize;
+                }
+            }
+        }
+    }
+}
+
+impl Sequencer for Music {
+    fn tick(&mut self, synth:&mut Synth) {
+        let Some(song) = self.song else { return };
+        if self.countdown == 0 {
+            self.countdown = song.samples_per_row;
+            self.step_row(synth, song);
+        }
+        self.countdown = self.countdown.saturating_sub(1);
+    }
+}
+
+// Unit tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn play_song() {
+        // 1 channel, 1 instrument, 1 pattern of 2 rows, 2 samples per row, no loop
+        static SONG:[u8; 24] = [VERSION, 2, 0, 1, 1, 1, 2, 1, NO_LOOP,
+            1, 128, 255, 255, 0, 0, 0, 0, 0, 0, // Square instrument
+            0, // Order
+            69, 0, NOTE_OFF, INSTRUMENT_KEEP]; // A4, then release
+
+        let mut synth = Synth::new();
+        let mut music = Music::new(VOICE_COUNT - MAX_CHANNELS);
+        music.play(&mut synth, &SONG);
+        let voice = music.first_voice;
+
+        music.tick(&mut synth);
+        assert!(synth.voices[voice].active(), "First row should start note");
+        assert_eq!(synth.voices[voice].freq, hz(440), "A4 should be 440hz");
+        assert_eq!(synth.voices[voice].waveform, Waveform::Square);
+        for _ in 0..2 { music.tick(&mut synth); synth.sample(); }
+        assert!(!synth.voices[voice].active(), "Second row should release note");
+        assert!(music.song.is_none(), "Song without loop should stop at end");
+    }
+
+    #[test]
+    fn converted_song() { // The converter and the player agree on the format
+        let text = "tempo 600\nrows 1\ninstrument 0 triangle\ninstrument 1 saw\npattern 0\nC-5 1\norder 0\n";
+        let song = alloc::vec::Vec::leak(crate::tracker::convert(text).unwrap());
+        let mut synth = Synth::new();
+        let mut music = Music::new(VOICE_COUNT - MAX_CHANNELS);
+        music.play(&mut synth, song);
+        music.tick(&mut synth);
+        let voice = &synth.voices[music.first_voice];
+        assert_eq!(voice.freq, note_freq(72), "C5");
+        assert_eq!(voice.waveform, Waveform::Saw, "Second instrument");
+    }
+}
@@ -0,0 +1,163 @@
+// Frame profiler: cycle (mcycle) and instruction (minstret) counts for named sections of the frame,
+// collected into per-frame min/avg/max and a histogram, and dumped over UART on demand.
+// Notes:
+//    Only active with the speed-debug feature; otherwise every method is an empty inline function.
+//    Time is always charged to the current scope; switch() changes it and returns the old one, so a
+//    section nested in another (audio refills during the vblank wait) is switch(X) ... switch(old).
+//    Idle is the vblank wait and isn't counted in the "total" row.
+//    A scope can come and go several times in one frame (audio does); the times are summed.
+//    Counts are the low 32 bits of the counters, so a frame must be under 2^32 cycles.
+//    The histogram buckets are tenths of the frame budget, the last one for "over budget".
+
+use litex_openfpga::println;
+
+pub const ENABLED:bool = cfg!(feature = "speed-debug");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Idle,    // Waiting for vblank
+    Input,
+    Physics, // Game mechanics, including the small erase/redraws that go with them
+    Draw,    // Full-screen redraws
+    Audio,
+}
+
+pub const SCOPE_COUNT:usize = 5;
+const BUCKETS:usize = 11;
+
+impl Scope {
+    pub const ALL:[Scope; SCOPE_COUNT] = [Scope::Idle, Scope::Input, Scope::Physics, Scope::Draw, Scope::Audio];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scope::Idle => "idle",
+            Scope::Input => "input",
+            Scope::Physics => "physics",
+            Scope::Draw => "draw",
+            Scope::Audio => "audio",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Stamp {
+    cycles: u32,
+    instret: u32,
+}
+
+#[inline(always)]
+fn stamp() -> Stamp {
+    if !ENABLED { return Stamp { cycles:0, instret:0 } }
+    Stamp {
+        cycles: riscv::register::mcycle::read() as u32,
+        instret: riscv::register::minstret::read() as u32,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Stats {
+    min: u32,
+    max: u32,
+    cycles: u64,  // Total over all frames
+    instret: u64,
+    histogram: [u32; BUCKETS],
+}
+
+impl Stats {
+    const EMPTY:Stats = Stats { min:u32::MAX, max:0, cycles:0, instret:0, histogram:[0; BUCKETS] };
+
+    fn add(&mut self, cycles:u32, instret:u32, budget:u32) {
+        self.min = self.min.min(cycles);
+        self.max = self.max.max(cycles);
+        self.cycles += cycles as u64;
+        self.instret += instret as u64;
+        let bucket = if cycles >= budget { BUCKETS-1 } else { (cycles * (BUCKETS as u32-1) / budget.max(1)) as usize };
+        self.histogram[bucket] += 1;
+    }
+
+    fn print(&self, name:&str, frames:u32) {
+        if frames == 0 { return }
+        let avg = self.cycles / frames as u64;
+        let cpi = (self.cycles * 100).checked_div(self.instret).unwrap_or(0); // Cycles per instruction, hundredths
+        println!("{:8} min {:8} avg {:8} max {:8} cpi {}.{:02} hist {:?}",
+            name, self.min, avg, self.max, cpi / 100, cpi % 100, self.histogram);
+    }
+}
+
+pub struct Profiler {
+    current: Scope,
+    since: Stamp, // When current scope was entered
+    frame: [(u32, u32); SCOPE_COUNT], // Cycles and instructions so far this frame
+    stats: [Stats; SCOPE_COUNT],
+    total: Stats, // All scopes together
+    frames: u32,
+    budget: u32,  // Cycles in the most recent frame's budget
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler { current:Scope::Idle, since:stamp(), frame:[(0, 0); SCOPE_COUNT], stats:[Stats::EMPTY; SCOPE_COUNT], total:Stats::EMPTY, frames:0, budget:0 }
+    }
+
+    // Charge time so far to the current scope, and make scope current. Returns the old scope.
+    #[inline(always)]
+    pub fn switch(&mut self, scope:Scope) -> Scope {
+        if !ENABLED { return scope }
+        let now = stamp();
+        let frame = &mut self.frame[self.current as usize];
+        frame.0 = frame.0.wrapping_add(now.cycles.wrapping_sub(self.since.cycles));
+        frame.1 = frame.1.wrapping_add(now.instret.wrapping_sub(self.since.instret));
+        self.since = now;
+        core::mem::replace(&mut self.current, scope)
+    }
+
+    // Fold the frame's times into the stats. Call at vblank; budget is the length of a frame in cycles.
+    pub fn end_frame(&mut self, budget:u32) {
+        if !ENABLED { return }
+        self.switch(self.current);
+        let (mut cycles, mut instret) = (0u32, 0u32);
+        for (scope, (stats, frame)) in Scope::ALL.iter().zip(self.stats.iter_mut().zip(&mut self.frame)) {
+            stats.add(frame.0, frame.1, budget);
+            if *scope != Scope::Idle {
+                cycles = cycles.wrapping_add(frame.0);
+                instret = instret.wrapping_add(frame.1);
+            }
+            *frame = (0, 0);
+        }
+        self.total.add(cycles, instret, budget);
+        self.frames += 1;
+        self.budget = budget;
+    }
+
+    // Print everything collected since the last dump, then start over
+    pub fn dump(&mut self, missed:u32, underruns:u32) {
+        if !ENABLED { return }
+        println!("Profile: {} frames, budget {} cycles/frame, {} missed frames, {} audio underruns",
+            self.frames, self.budget, missed, underruns);
+        for scope in Scope::ALL {
+            self.stats[scope as usize].print(scope.name(), self.frames);
+        }
+        self.total.print("total", self.frames);
+        self.stats = [Stats::EMPTY; SCOPE_COUNT];
+        self.total = Stats::EMPTY;
+        self.frames = 0;
+    }
+}
+
+// Unit tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram() {
+        let mut stats = Stats::EMPTY;
+        stats.add(0, 0, 1000);
+        stats.add(550, 0, 1000);
+        stats.add(999, 0, 1000);
+        stats.add(5000, 0, 1000);
+        assert_eq!((stats.min, stats.max), (0, 5000));
+        assert_eq!(stats.histogram, [1, 0, 0, 0, 0, 1, 0, 0, 0, 1, 1], "Buckets should be tenths of budget, then over");
+    }
+}
@@ -0,0 +1,47 @@
+// Tiny xorshift random number generator, for gameplay (power-up drops and such). Not good, just cheap.
+// Notes:
+//    below() scales with a multiply instead of taking a remainder, so there's no division on RV32.
+
+pub struct Rng(u32);
+
+impl Rng {
+    pub fn new(seed:u32) -> Self {
+        Rng(seed.max(1)) // xorshift is stuck forever at 0
+    }
+
+    pub fn next(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    // 0..n
+    pub fn below(&mut self, n:u32) -> u32 {
+        ((self.next() as u64 * n as u64) >> 32) as u32
+    }
+
+    // True num times in den
+    pub fn chance(&mut self, num:u32, den:u32) -> bool {
+        self.below(den) < num
+    }
+}
+
+// Unit tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spread() {
+        let mut rng = Rng::new(0);
+        let mut counts = [0; 6];
+        for _ in 0..6000 { counts[rng.below(6) as usize] += 1; }
+        assert!(counts.iter().all(|&c| (800..1200).contains(&c)), "below() should be roughly even: {:?}", counts);
+        let hits = (0..6000).filter(|_| rng.chance(1, 6)).count();
+        assert!((800..1200).contains(&hits), "chance(1, 6) should hit about 1 in 6: {}", hits);
+    }
+}
@@ -0,0 +1,174 @@
+// Persistent settings and high scores, stored in a data slot through the APF bridge.
+// Notes:
+//    This requires the core's data.json to define a nonvolatile slot with id SAVE_SLOT_ID, at least
+//    SAVE_SIZE bytes long. If the slot is missing, empty or corrupt, load() returns None and the
+//    caller should fall back on defaults.
+//    The bridge DMAs directly into RAM, so the save buffer is a static rather than on the heap/stack.
+//    Version 1 saves (bindings only) still load, with an empty high score table.
+
+use litex_pac as pac;
+use crate::input::{Bindings, ACTION_COUNT, CONTROLLER_COUNT};
+
+const SAVE_SLOT_ID:u32 = 10;
+const SAVE_MAGIC:[u8;4] = *b"MBRK";
+const SAVE_VERSION:u8 = 2;
+const SAVE_SIZE:usize = 64;
+pub const HIGH_SCORE_COUNT:usize = 4;
+
+const BRIDGE_TIMEOUT:u32 = 10_000_000; // Spins before we give up on the bridge
+
+static mut SAVE_BUFFER:[u8; SAVE_SIZE] = [0; SAVE_SIZE];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Save {
+    pub bindings: [Bindings; CONTROLLER_COUNT],
+    pub high_scores: [u32; HIGH_SCORE_COUNT], // Best first; 0 is an empty entry
+}
+
+// Simple rolling checksum; catches an empty or half-written slot
+fn checksum(bytes:&[u8]) -> u16 {
+    bytes.iter().fold(0u16, |sum, &b| sum.wrapping_mul(31).wrapping_add(b as u16))
+}
+
+impl Save {
+    // Put a score in the high score table if it makes it. Returns its rank (0 is best), or None.
+    pub fn add_high_score(&mut self, score:u32) -> Option<usize> {
+        let rank = self.high_scores.iter().position(|&high| score > high)?;
+        self.high_scores.copy_within(rank..HIGH_SCORE_COUNT-1, rank+1);
+        self.high_scores[rank] = score;
+        Some(rank)
+    }
+
+    // Layout: magic, version, bindings as little-endian u16s, high scores as little-endian u32s,
+    // checksum of everything before it
+    pub fn to_bytes(self) -> [u8; SAVE_SIZE] {
+        let mut bytes = [0u8; SAVE_SIZE];
+        let mut at = 0;
+        let mut put = |data:&[u8]| { bytes[at..at+data.len()].copy_from_slice(data); at += data.len(); };
+        put(&SAVE_MAGIC);
+        put(&[SAVE_VERSION]);
+        for bindings in &self.bindings {
+            for buttons in bindings.buttons {
+                put(&buttons.to_le_bytes());
+            }
+        }
+        for score in self.high_scores {
+            put(&score.to_le_bytes());
+        }
+        let sum = checksum(&bytes[..at]);
+        bytes[at..at+2].copy_from_slice(&sum.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes:&[u8; SAVE_SIZE]) -> Option<Self> {
+        let version = bytes[4];
+        if bytes[0..4] != SAVE_MAGIC || !(1..=SAVE_VERSION).contains(&version) { return None }
+
+        let mut save = Save::default();
+        let mut at = 5;
+        for bindings in &mut save.bindings {
+            for buttons in &mut bindings.buttons {
+                *buttons = u16::from_le_bytes([bytes[at], bytes[at+1]]);
+                at += 2;
+            }
+        }
+        if version >= 2 {
+            for score in &mut save.high_scores {
+                *score = u32::from_le_bytes([bytes[at], bytes[at+1], bytes[at+2], bytes[at+3]]);
+                at += 4;
+            }
+        }
+        let sum = u16::from_le_bytes([bytes[at], bytes[at+1]]);
+        if sum != checksum(&bytes[..at]) { return None }
+
+        // Every action must be reachable, or a bad save could lock a player out of the menu
+        let reachable = save.bindings.iter().all(|b| b.buttons.iter().all(|&buttons| buttons != 0));
+        if !reachable { return None }
+
+        Some(save)
+    }
+}
+
+const _:() = assert!(5 + CONTROLLER_COUNT*ACTION_COUNT*2 + HIGH_SCORE_COUNT*4 + 2 <= SAVE_SIZE, "SAVE_SIZE too small");
+
+// Wait for bridge to finish its current request. False if it never did.
+fn bridge_wait(bridge:&pac::APF_BRIDGE) -> bool {
+    for _ in 0..BRIDGE_TIMEOUT {
+        if bridge.status.read().bits() == 0 { return true }
+    }
+    false
+}
+
+// Start a bridge transfer between the save slot and SAVE_BUFFER
+fn bridge_request(bridge:&pac::APF_BRIDGE, write:bool) -> bool {
+    if !bridge_wait(bridge) { return false }
+    unsafe {
+        bridge.slot_id.write(|w| w.bits(SAVE_SLOT_ID));
+        bridge.data_offset.write(|w| w.bits(0));
+        bridge.length.write(|w| w.bits(SAVE_SIZE as u32));
+        bridge.ram_data_address.write(|w| w.bits(core::ptr::addr_of!(SAVE_BUFFER) as u32));
+        if write {
+            bridge.request_write.write(|w| w.bits(1));
+        } else {
+            bridge.request_read.write(|w| w.bits(1));
+        }
+    }
+    bridge_wait(bridge)
+}
+
+pub fn load(bridge:&pac::APF_BRIDGE) -> Option<Save> {
+    if !bridge_request(bridge, false) { return None }
+    // The DMA'd data may not be visible through the data cache yet
+    #[cfg(target_arch = "riscv32")]
+    unsafe { core::arch::asm!(".word 0x500F"); } // VexRiscv flush dcache
+    let bytes = unsafe { core::ptr::addr_of!(SAVE_BUFFER).read_volatile() };
+    Save::from_bytes(&bytes)
+}
+
+pub fn store(bridge:&pac::APF_BRIDGE, save:&Save) -> bool {
+    unsafe { core::ptr::addr_of_mut!(SAVE_BUFFER).write_volatile(save.to_bytes()); }
+    bridge_request(bridge, true)
+}
+
+// Unit tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut save = Save::default();
+        save.bindings[1].buttons[0] = 1<<6;
+        save.high_scores[0] = 12345;
+        let bytes = save.to_bytes();
+        assert_eq!(Save::from_bytes(&bytes), Some(save), "Save should survive round trip");
+
+        let mut corrupt = bytes;
+        corrupt[7] ^= 1;
+        assert_eq!(Save::from_bytes(&corrupt), None, "Corrupt save should be rejected");
+        assert_eq!(Save::from_bytes(&[0; SAVE_SIZE]), None, "Empty slot should be rejected");
+
+        // A version 1 save is the same minus the scores
+        let mut old = [0u8; SAVE_SIZE];
+        let len = 5 + CONTROLLER_COUNT*ACTION_COUNT*2;
+        old[..len].copy_from_slice(&bytes[..len]);
+        old[4] = 1;
+        let sum = checksum(&old[..len]);
+        old[len..len+2].copy_from_slice(&sum.to_le_bytes());
+        let loaded = Save::from_bytes(&old).expect("Version 1 save should load");
+        assert_eq!((loaded.bindings, loaded.high_scores), (save.bindings, [0; HIGH_SCORE_COUNT]));
+    }
+
+    #[test]
+    fn high_scores() {
+        let mut save = Save::default();
+        assert_eq!(save.add_high_score(100), Some(0));
+        assert_eq!(save.add_high_score(300), Some(0));
+        assert_eq!(save.add_high_score(200), Some(1));
+        assert_eq!(save.add_high_score(0), None, "Zero should never make the table");
+        assert_eq!(save.add_high_score(50), Some(3));
+        assert_eq!(save.add_high_score(10), None, "Table is full");
+        assert_eq!(save.high_scores, [300, 200, 100, 50]);
+    }
+}
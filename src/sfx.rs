@@ -0,0 +1,162 @@
+// sfxr-style sound effects: each effect is a small description of a sound (waveform, pitch sweep,
+// envelope, vibrato, arpeggio...), and SfxPlayer plays them by id on a pool of synth voices.
+// Notes:
+//    Effects take voices 0..SFX_VOICES. Music should stay out of that range.
+//    Frequencies are synth phase steps, so use hz() or freq16() to write them.
+//    An effect's length is attack+sustain+decay; sweeps run over exactly that length.
+
+use crate::synth::*;
+
+pub const SFX_VOICES:usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sfx {
+    pub waveform: Waveform,
+    pub freq: u32,          // Phase step at start
+    pub freq_end: u32,      // Phase step at end; pitch slides linearly from freq
+    pub duty: u32,          // Square only, out of 2^32
+    pub duty_end: u32,      // Duty at end; slides linearly from duty
+    pub attack: u32,        // Samples to reach full volume
+    pub sustain: u32,       // Samples held at full volume
+    pub decay: u32,         // Samples to fade out
+    pub vibrato_depth: u16, // Pitch wobble, fraction of freq out of 256
+    pub vibrato_rate: u32,  // Phase step of the vibrato oscillator
+    pub arpeggio_time: u32, // Samples before pitch jumps (0 for no jump)
+    pub arpeggio_mul: u32,  // Pitch multiplier at the jump, out of 256 (512 is up an octave)
+    pub strobe: u32,        // Sound on/off period in samples (0 for steady)
+    pub volume: u16,
+}
+
+impl Sfx {
+    // Short, steady saw blip; effects override what they need
+    pub const DEFAULT:Sfx = Sfx {
+        waveform: Waveform::Saw,
+        freq: hz(440), freq_end: hz(440),
+        duty: 1<<31, duty_end: 1<<31,
+        attack: 0, sustain: 1600, decay: 0,
+        vibrato_depth: 0, vibrato_rate: 0,
+        arpeggio_time: 0, arpeggio_mul: 256,
+        strobe: 0,
+        volume: VOLUME_MAX,
+    };
+
+    pub const fn length(&self) -> u32 {
+        self.attack + self.sustain + self.decay
+    }
+
+    // Set up voice to play this effect, and start it
+    pub fn apply(&self, voice:&mut Voice) {
+        let length = self.length().max(1) as i64;
+        let slide = |from:u32, to:u32| ((to as i64 - from as i64) / length) as i32;
+
+        voice.waveform = self.waveform;
+        voice.freq = self.freq;
+        voice.slide = slide(self.freq, self.freq_end);
+        voice.duty = self.duty;
+        voice.duty_slide = slide(self.duty, self.duty_end);
+        voice.volume = self.volume;
+        voice.adsr = Adsr { attack:self.attack, decay:0, sustain:ENV_MAX, release:self.decay };
+        voice.vibrato_depth = self.vibrato_depth;
+        voice.vibrato_rate = self.vibrato_rate;
+        voice.arpeggio = if self.arpeggio_time > 0 { Some((self.arpeggio_time, self.arpeggio_mul)) } else { None };
+        voice.tremolo = if self.strobe > 0 { Tremolo::Strobe(self.strobe) } else { Tremolo::None };
+        voice.trigger(self.attack + self.sustain);
+    }
+}
+
+// The game's sounds. BASE is the step of the game's original sawtooth; everything is tuned off it.
+const BASE:u16 = 150;
+const BLOOP_STROBE:u32 = 8*800; // Length of one bloop pulse
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SfxId {
+    Bounce, // Ball off a wall or paddle: base + 1 octave
+    Hit,    // Ball destroys a vader: base + 2 octaves
+    Lose,   // Strobing bloop at base - 1 octave
+    Win,    // Strobing bloop at base + 2 octaves
+    Clear,  // Level cleared: square rising from base + 1 octave, jumping up a fifth partway
+    Explode, // Bomb vader: noise falling from base + 2 octaves
+    Power,  // Capsule caught: quick triangle sweep up from base + 1 octave to base + 3
+    Miss,   // Lost a life: saw sliding down from base to base - 1 octave
+    Shoot,  // Vader fires: thin square zapping down from base + 3 octaves to base + 1
+    Hurt,   // Shot hits a paddle: noise crunching down from base + 1 octave to base - 2
+}
+
+impl SfxId {
+    // Matched rather than indexed, so the ids can be reordered without mixing up sounds
+    pub fn sfx(self) -> Sfx {
+        match self {
+            SfxId::Bounce => Sfx { freq:freq16(BASE*2), freq_end:freq16(BASE*2), ..Sfx::DEFAULT },
+            SfxId::Hit => Sfx { freq:freq16(BASE*4), freq_end:freq16(BASE*4), ..Sfx::DEFAULT },
+            SfxId::Lose => Sfx { freq:freq16(BASE/2), freq_end:freq16(BASE/2), sustain:BLOOP_STROBE*6, strobe:BLOOP_STROBE*2, ..Sfx::DEFAULT },
+            SfxId::Win => Sfx { freq:freq16(BASE*4), freq_end:freq16(BASE*4), sustain:BLOOP_STROBE*6, strobe:BLOOP_STROBE*2, ..Sfx::DEFAULT },
+            SfxId::Clear => Sfx { waveform:Waveform::Square, freq:freq16(BASE*2), freq_end:freq16(BASE*3), sustain:9600, decay:9600,
+                arpeggio_time:6400, arpeggio_mul:384, ..Sfx::DEFAULT },
+            SfxId::Explode => Sfx { waveform:Waveform::Noise, freq:freq16(BASE*4), freq_end:freq16(BASE/2), sustain:2400, decay:9600, ..Sfx::DEFAULT },
+            SfxId::Power => Sfx { waveform:Waveform::Triangle, freq:freq16(BASE*2), freq_end:freq16(BASE*8), sustain:4800, decay:2400, ..Sfx::DEFAULT },
+            SfxId::Miss => Sfx { freq:freq16(BASE), freq_end:freq16(BASE/2), sustain:9600, decay:4800, ..Sfx::DEFAULT },
+            SfxId::Shoot => Sfx { waveform:Waveform::Square, freq:freq16(BASE*8), freq_end:freq16(BASE*2), duty:1<<29, duty_end:1<<29,
+                sustain:1200, decay:1200, volume:VOLUME_MAX/2, ..Sfx::DEFAULT },
+            SfxId::Hurt => Sfx { waveform:Waveform::Noise, freq:freq16(BASE*2), freq_end:freq16(BASE/4), sustain:4800, decay:4800, ..Sfx::DEFAULT },
+        }
+    }
+}
+
+pub struct SfxPlayer {
+    playing: [Option<SfxId>; SFX_VOICES], // Effect last started on each voice
+    next: usize, // Voice to steal when none are free
+}
+
+impl SfxPlayer {
+    pub fn new() -> Self {
+        SfxPlayer { playing:[None; SFX_VOICES], next:0 }
+    }
+
+    // Start an effect at a stereo position. A repeat of a still-playing effect restarts it on the
+    // same voice; otherwise it takes a free voice, or steals the oldest.
+    pub fn play(&mut self, synth:&mut Synth, id:SfxId, pan:i32) {
+        let voice = (0..SFX_VOICES).find(|&v| self.playing[v] == Some(id) && synth.voices[v].active())
+            .or_else(|| (0..SFX_VOICES).find(|&v| !synth.voices[v].active()))
+            .unwrap_or_else(|| { let v = self.next; self.next = (v + 1) % SFX_VOICES; v });
+        self.playing[voice] = Some(id);
+        let voice = &mut synth.voices[voice];
+        voice.pan = pan;
+        id.sfx().apply(voice);
+    }
+
+    pub fn playing(&self, synth:&Synth, id:SfxId) -> bool {
+        (0..SFX_VOICES).any(|v| self.playing[v] == Some(id) && synth.voices[v].active())
+    }
+
+    pub fn stop_all(&mut self, synth:&mut Synth) {
+        for v in 0..SFX_VOICES { synth.voices[v].silence(); }
+        self.playing = [None; SFX_VOICES];
+    }
+}
+
+// Unit tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_and_length() {
+        let sfx = Sfx { freq:hz(1000), freq_end:hz(500), sustain:100, ..Sfx::DEFAULT };
+        let mut synth = Synth::new();
+        let mut player = SfxPlayer::new();
+        synth.voices[0] = Voice::new(Waveform::Saw);
+        sfx.apply(&mut synth.voices[0]);
+        for _ in 0..100 { synth.sample(); }
+        let freq = synth.voices[0].freq;
+        assert!(freq.abs_diff(hz(500)) < hz(5), "Sweep should reach freq_end by the end");
+        synth.sample(); synth.sample();
+        assert!(!synth.voices[0].active(), "Effect should stop after its length");
+
+        player.play(&mut synth, SfxId::Bounce, 0);
+        player.play(&mut synth, SfxId::Hit, 0);
+        assert!(player.playing(&synth, SfxId::Bounce) && player.playing(&synth, SfxId::Hit), "Different effects should overlap");
+        player.play(&mut synth, SfxId::Bounce, 0);
+        assert_eq!((0..SFX_VOICES).filter(|&v| synth.voices[v].active()).count(), 2, "Repeated effect should reuse its voice");
+    }
+}
@@ -0,0 +1,19 @@
+// Binary song format, shared by the converter (tracker.rs, run by build.rs) and the player (music.rs)
+// so the two can't disagree. Keep this file to constants; build.rs includes it too.
+//
+// Layout (all multi-byte values little endian):
+//    version u8, samples_per_row u16, channels u8, instrument_count u8, pattern_count u8,
+//    rows u8, order_len u8, loop u8 (NO_LOOP for no loop)
+//    instruments: waveform u8, duty u8, volume u8, sustain u8, attack u16, decay u16, release u16
+//    order: order_len bytes
+//    patterns: pattern_count*rows*channels cells of note u8 (NOTE_NONE, NOTE_OFF, else MIDI note),
+//    instrument u8 (INSTRUMENT_KEEP for the channel's last one)
+
+pub const VERSION:u8 = 1;
+pub const HEADER_SIZE:usize = 9;
+pub const INSTRUMENT_SIZE:usize = 10;
+pub const MAX_CHANNELS:usize = 4;
+pub const NOTE_NONE:u8 = 0;
+pub const NOTE_OFF:u8 = 0xFF;
+pub const INSTRUMENT_KEEP:u8 = 0xFF;
+pub const NO_LOOP:u8 = 0xFF;
@@ -0,0 +1,505 @@
+// Small software synthesizer: a handful of voices that play at once, mixed into APF_AUDIO samples.
+// Notes:
+//    Oscillator phase is a u32 where 2^32 is one full cycle, so "freq" is the phase step per sample.
+//    Use hz() to convert from a frequency, or freq16() to convert from the 16-bit sawtooth step
+//    the game originally used (phase wrapping at 2^16).
+//    Output is stereo; each voice has a pan from -PAN_MAX (left) to PAN_MAX (right).
+//    Saw and square are band-limited with PolyBLEP (a small correction near each jump in the wave),
+//    so high notes don't alias into inharmonic whine. Triangle has no jumps and aliases little;
+//    noise is left raw.
+//    The mix can also go through a one-pole low-pass and a DC blocker; both are off by default.
+//    Voices and envelopes are all integer math; nothing here touches the FPU. The per-sample path
+//    avoids 64-bit division, which on RV32 is a slow library call.
+
+use litex_pac as pac;
+
+pub const SAMPLE_RATE:u32 = 48000;
+pub const VOICE_COUNT:usize = 8;
+
+pub const ENV_MAX:u32 = 1<<16; // Envelope level at full volume
+const ENV_SHIFT:u32 = 16;
+pub const VOLUME_MAX:u16 = 256; // Voice/master volume at unity
+const VOLUME_SHIFT:u32 = 8;
+
+pub const PAN_MAX:i32 = 1<<PAN_SHIFT;
+const PAN_SHIFT:u32 = 7;
+
+// Mixed voices are scaled down by this many bits. At 4, one full-volume voice peaks at +-2^11.
+const MIX_SHIFT:u32 = 4;
+
+// Phase step for a frequency in Hz
+pub const fn hz(hz:u32) -> u32 {
+    (((hz as u64) << 32) / SAMPLE_RATE as u64) as u32
+}
+
+// Phase step for a step of a 16-bit phase accumulator
+pub const fn freq16(delta:u16) -> u32 {
+    (delta as u32) << 16
+}
+
+// Pan for a position within min..=max, so min is hard left and max is hard right
+pub fn pan_position(pos:i32, min:i32, max:i32) -> i32 {
+    if max <= min { return 0 }
+    ((pos.clamp(min, max) - min) * 2*PAN_MAX / (max - min)) - PAN_MAX
+}
+
+// Triangle wave of a phase, +-2^15
+fn triangle(phase:u32) -> i32 {
+    let p = (phase >> 15) as i32; // 0..2^17
+    if p < 0x10000 { p - 0x8000 } else { 0x17FFF - p }
+}
+
+// PolyBLEP correction for a downward jump of 2 (+1 to -1) at phase 0, +-2^15. Subtract it from a
+// naive wave with such a jump (add it for an upward jump). Zero except within one sample of the jump.
+fn polyblep(phase:u32, freq:u32) -> i32 {
+    if freq == 0 || freq >= 1<<31 { return 0 } // Nothing sensible to do above Nyquist
+    let (dist, after) = if phase < freq { (phase, true) }
+        else if phase.wrapping_neg() <= freq { (phase.wrapping_neg(), false) }
+        else { return 0 };
+    // dist/freq as 16-bit fixed point. Normalize first so this is a 32-bit division.
+    let shift = freq.leading_zeros();
+    let x = ((dist << shift) / ((freq << shift) >> 16)) as i64; // 0..=2^16
+    let x2 = (x * x) >> 16;
+    let value = if after { 2*x - x2 - 0x10000 } else { x2 - 2*x + 0x10000 };
+    (value >> 1) as i32
+}
+
+// One-pole low-pass coefficient (for Synth::lowpass) with a cutoff at this frequency
+pub const fn lowpass_coefficient(cutoff_hz:u32) -> u32 {
+    // w = 2*pi*cutoff/rate, coefficient = w/(1+w), both 16-bit fixed point
+    let w = cutoff_hz as u64 * 411775 / SAMPLE_RATE as u64;
+    ((w << 16) / (0x10000 + w)) as u32
+}
+
+// Pole of the DC blocker, 16-bit fixed point (about 0.995, a ~40hz cutoff)
+const DC_POLE:i64 = 65209;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Waveform {
+    #[default]
+    Saw,
+    Square,   // Uses Voice::duty
+    Triangle,
+    Noise,    // Pitched: a new random value each cycle
+}
+
+// Attack/decay/release are lengths in samples (0 for instant), sustain is a level 0..=ENV_MAX
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Adsr {
+    pub attack: u32,
+    pub decay: u32,
+    pub sustain: u32,
+    pub release: u32,
+}
+
+impl Adsr {
+    // Instant on, instant off
+    pub const GATE:Adsr = Adsr { attack:0, decay:0, sustain:ENV_MAX, release:0 };
+}
+
+impl Default for Adsr {
+    fn default() -> Self { Adsr::GATE }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Stage {
+    #[default]
+    Off,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+// Amplitude modulation by a slow oscillator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Tremolo {
+    #[default]
+    None,
+    Strobe(u32), // Voice sounds for the first half of this many samples, then is silent for the second half
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Voice {
+    pub waveform: Waveform,
+    pub freq: u32,     // Phase step per sample
+    pub duty: u32,     // Square wave: portion of the cycle spent high, out of 2^32
+    pub volume: u16,   // 0..=VOLUME_MAX
+    pub pan: i32,      // -PAN_MAX..=PAN_MAX, 0 is center
+    pub adsr: Adsr,
+    pub tremolo: Tremolo,
+    pub tremolo_phase: u32, // Position in the tremolo period, where 2^32 is one period
+    pub slide: i32,         // Added to freq every sample (frequency sweep)
+    pub duty_slide: i32,    // Added to duty every sample
+    pub vibrato_depth: u16, // Pitch wobble, as a fraction of freq out of 256
+    pub vibrato_rate: u32,  // Phase step of the (triangle) vibrato oscillator
+    pub arpeggio: Option<(u32, u32)>, // (samples from now, multiplier out of 256): freq jumps once
+    vibrato_phase: u32,
+    stage: Stage,
+    level: u32,        // Current envelope level, 0..=ENV_MAX
+    gate: Option<u32>, // Samples left before automatic note_off
+    phase: u32,
+    noise: u16,        // LFSR state for Waveform::Noise
+}
+
+impl Voice {
+    pub fn new(waveform:Waveform) -> Self {
+        Voice { waveform, duty:1<<31, volume:VOLUME_MAX, noise:0xACE1, ..Default::default() }
+    }
+
+    // Start (or restart) the envelope. The note holds at sustain until note_off().
+    pub fn note_on(&mut self) {
+        self.stage = Stage::Attack;
+        self.gate = None;
+        self.tremolo_phase = 0;
+        self.vibrato_phase = 0;
+    }
+
+    // Start the envelope, and note_off() automatically after this many samples
+    pub fn trigger(&mut self, samples:u32) {
+        self.note_on();
+        self.gate = Some(samples);
+    }
+
+    pub fn note_off(&mut self) {
+        if self.stage != Stage::Off { self.stage = Stage::Release; }
+        self.gate = None;
+    }
+
+    // Stop immediately, skipping release
+    pub fn silence(&mut self) {
+        self.stage = Stage::Off;
+        self.level = 0;
+        self.gate = None;
+    }
+
+    // Still making sound (including release)
+    pub fn active(&self) -> bool {
+        self.stage != Stage::Off
+    }
+
+    fn envelope(&mut self) -> u32 {
+        if let Some(gate) = self.gate {
+            if gate == 0 { self.note_off(); } else { self.gate = Some(gate-1); }
+        }
+
+        let adsr = self.adsr;
+        let step = |length:u32, span:u32| span.checked_div(length).map_or(span, |s| s.max(1)); // Length 0 is instant
+        match self.stage {
+            Stage::Off => { self.level = 0; }
+            Stage::Attack => {
+                self.level = (self.level + step(adsr.attack, ENV_MAX)).min(ENV_MAX);
+                if self.level == ENV_MAX { self.stage = Stage::Decay; }
+            }
+            Stage::Decay => {
+                let sustain = adsr.sustain.min(ENV_MAX);
+                self.level = self.level.saturating_sub(step(adsr.decay, ENV_MAX - sustain)).max(sustain);
+                if self.level == sustain { // A 0 sustain is a percussive sound, and is over
+                    self.stage = if sustain == 0 { Stage::Off } else { Stage::Sustain };
+                }
+            }
+            Stage::Sustain => {
+                self.level = adsr.sustain.min(ENV_MAX);
+            }
+            Stage::Release => {
+                self.level = self.level.saturating_sub(step(adsr.release, ENV_MAX));
+                if self.level == 0 { self.stage = Stage::Off; }
+            }
+        }
+        self.level
+    }
+
+    // Tremolo multiplier, 0..=ENV_MAX
+    fn tremolo(&mut self) -> u32 {
+        match self.tremolo {
+            Tremolo::None => ENV_MAX,
+            Tremolo::Strobe(period) => {
+                let phase = self.tremolo_phase;
+                self.tremolo_phase = phase.wrapping_add(u32::MAX / period.max(1));
+                if phase < 1<<31 { ENV_MAX } else { 0 }
+            }
+        }
+    }
+
+    // Apply sweeps/arpeggio to freq and duty, and return this sample's frequency after vibrato
+    fn modulate(&mut self) -> u32 {
+        if self.slide != 0 { self.freq = self.freq.saturating_add_signed(self.slide).max(1); }
+        if self.duty_slide != 0 { self.duty = self.duty.saturating_add_signed(self.duty_slide); }
+        if let Some((time, mul)) = self.arpeggio {
+            if time == 0 {
+                self.freq = ((self.freq as u64 * mul as u64) >> 8) as u32;
+                self.arpeggio = None;
+            } else {
+                self.arpeggio = Some((time-1, mul));
+            }
+        }
+
+        if self.vibrato_depth == 0 { return self.freq }
+        let wobble = triangle(self.vibrato_phase) as i64;
+        self.vibrato_phase = self.vibrato_phase.wrapping_add(self.vibrato_rate);
+        let offset = (self.freq as i64 * self.vibrato_depth as i64 * wobble) >> (8 + 15);
+        (self.freq as i64 + offset).max(1) as u32
+    }
+
+    // Raw oscillator output, +-2^15, then advance phase
+    fn oscillator(&mut self, freq:u32) -> i32 {
+        let value = match self.waveform {
+            Waveform::Saw => (self.phase >> 16) as i32 - 0x8000 - polyblep(self.phase, freq),
+            Waveform::Square => {
+                let naive = if self.phase < self.duty { 0x7FFF } else { -0x8000 };
+                naive + polyblep(self.phase, freq) - polyblep(self.phase.wrapping_sub(self.duty), freq)
+            }
+            Waveform::Triangle => triangle(self.phase),
+            Waveform::Noise => if self.noise & 1 != 0 { 0x7FFF } else { -0x8000 },
+        };
+
+        let (phase, wrapped) = self.phase.overflowing_add(freq);
+        self.phase = phase;
+        if wrapped && self.waveform == Waveform::Noise { // 16-bit Galois LFSR
+            self.noise = (self.noise >> 1) ^ ((self.noise & 1).wrapping_neg() & 0xB400);
+        }
+        value
+    }
+
+    // One sample, +-2^15 at full volume
+    fn sample(&mut self) -> i32 {
+        if !self.active() { return 0 }
+        let level = (self.envelope() as u64 * self.tremolo() as u64) >> ENV_SHIFT;
+        let freq = self.modulate();
+        let value = (self.oscillator(freq) as i64 * level as i64) >> ENV_SHIFT;
+        ((value * self.volume as i64) >> VOLUME_SHIFT) as i32
+    }
+}
+
+// Something that changes voices as time passes, like the music player. fill() ticks it once per sample.
+pub trait Sequencer {
+    fn tick(&mut self, synth:&mut Synth);
+}
+
+// Filter state for one output channel
+#[derive(Debug, Clone, Copy, Default)]
+struct ChannelFilter {
+    lowpass: i32,
+    dc_in: i32,
+    dc_out: i32,
+}
+
+impl ChannelFilter {
+    fn run(&mut self, value:i32, lowpass:Option<u32>, dc_block:bool) -> i32 {
+        let mut value = value;
+        if let Some(coefficient) = lowpass {
+            self.lowpass += (((value - self.lowpass) as i64 * coefficient as i64) >> 16) as i32;
+            value = self.lowpass;
+        }
+        if dc_block {
+            self.dc_out = value - self.dc_in + ((self.dc_out as i64 * DC_POLE) >> 16) as i32;
+            self.dc_in = value;
+            value = self.dc_out;
+        }
+        value
+    }
+}
+
+pub struct Synth {
+    pub voices: [Voice; VOICE_COUNT],
+    pub master: u16,  // 0..=VOLUME_MAX
+    pub paused: bool, // While set, voices don't advance and the last sample is held
+    pub lowpass: Option<u32>, // Low-pass coefficient for the mix (see lowpass_coefficient), None for off
+    pub dc_block: bool, // Remove any constant offset from the mix
+    filters: [ChannelFilter; 2],
+    last: [i16; 2],
+}
+
+impl Synth {
+    pub fn new() -> Self {
+        Synth { voices:[Voice::new(Waveform::Saw); VOICE_COUNT], master:VOLUME_MAX, paused:false,
+            lowpass:None, dc_block:false, filters:Default::default(), last:[0; 2] }
+    }
+
+    // Back to silence, as if just created, but keep the mix settings (master, lowpass, dc_block)
+    pub fn reset(&mut self) {
+        for voice in &mut self.voices { *voice = Voice::new(Waveform::Saw); }
+        self.paused = false;
+        self.filters = Default::default();
+        self.last = [0; 2];
+    }
+
+    // Mix one stereo sample (left, right) from all voices
+    pub fn sample(&mut self) -> [i16; 2] {
+        if self.paused { return self.last }
+        let mut mix = [0i32; 2];
+        for voice in &mut self.voices {
+            let value = voice.sample();
+            if value == 0 { continue }
+            // Panning away from a side attenuates that side; center plays full volume on both
+            let pan = voice.pan.clamp(-PAN_MAX, PAN_MAX);
+            mix[0] += (value * (PAN_MAX - pan.max(0))) >> PAN_SHIFT;
+            mix[1] += (value * (PAN_MAX + pan.min(0))) >> PAN_SHIFT;
+        }
+        for (channel, filter) in mix.iter_mut().zip(&mut self.filters) {
+            *channel = filter.run(*channel, self.lowpass, self.dc_block);
+        }
+        self.last = mix.map(|channel| {
+            let channel = ((channel >> MIX_SHIFT) * self.master as i32) >> VOLUME_SHIFT;
+            channel.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+        });
+        self.last
+    }
+
+    // Generate enough samples to fill audio buffer up to target
+    pub fn fill(&mut self, audio:&pac::APF_AUDIO, target:i32, sequencer:&mut impl Sequencer) {
+        let needed = target - audio.buffer_fill.read().bits() as i32;
+        for _ in 0..needed {
+            if !self.paused { sequencer.tick(self); }
+            // Output value is two stereo i16s packed into one u32, left in the high half
+            let [left, right] = self.sample();
+            let value = ((left as u16 as u32) << 16) | right as u16 as u32;
+            unsafe { audio.out.write(|w| w.bits(value)) };
+        }
+    }
+}
+
+// Keeps the audio buffer topped up, independent of the frame loop. Call service() from as many
+// places as convenient; it does nothing until the buffer drains below low, then refills to high,
+// so calls are cheap and fills come in reasonably sized chunks.
+pub struct Refill {
+    pub low: i32,       // Buffer level (in samples) that triggers a refill
+    pub high: i32,      // Buffer level to refill to
+    pub underruns: u32, // Times the buffer was found empty after playback started
+    pub low_water: i32, // Lowest buffer level seen at a service() call since started
+    started: bool,
+}
+
+impl Refill {
+    pub fn new(low:i32, high:i32) -> Self {
+        Refill { low, high, underruns:0, low_water:high, started:false }
+    }
+
+    // Refill if needed. Returns true if the buffer had run dry (an underrun) since the last call.
+    pub fn service(&mut self, synth:&mut Synth, audio:&pac::APF_AUDIO, sequencer:&mut impl Sequencer) -> bool {
+        let level = audio.buffer_fill.read().bits() as i32;
+        let underrun = self.started && level == 0;
+        if self.started {
+            self.low_water = self.low_water.min(level);
+            if underrun { self.underruns += 1; }
+        }
+        if level < self.low || !self.started {
+            synth.fill(audio, self.high, sequencer);
+            self.started = true;
+        }
+        underrun
+    }
+}
+
+// Unit tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn envelope() {
+        let mut voice = Voice::new(Waveform::Square);
+        voice.freq = hz(1000);
+        voice.adsr = Adsr { attack:10, decay:10, sustain:ENV_MAX/2, release:10 };
+        assert_eq!(voice.sample(), 0, "Voice should be silent before note_on");
+
+        voice.trigger(30);
+        let peak = (0..20).map(|_| voice.sample().abs()).max().unwrap();
+        assert!(peak >= 0x7FF0, "Attack should reach full volume: {}", peak);
+        for _ in 0..10 { voice.sample(); }
+        assert_eq!(voice.level, ENV_MAX/2, "Decay should settle on sustain");
+        for _ in 0..20 { voice.sample(); }
+        assert!(!voice.active(), "Voice should finish release after gate");
+    }
+
+    #[test]
+    fn mixer_clamps() {
+        let mut synth = Synth::new();
+        synth.master = VOLUME_MAX*100;
+        for voice in &mut synth.voices {
+            voice.waveform = Waveform::Square;
+            voice.note_on();
+        }
+        assert_eq!(synth.sample(), [i16::MAX; 2], "Mix should clamp rather than wrap");
+        synth.paused = true;
+        assert_eq!(synth.sample(), [i16::MAX; 2], "Paused synth should hold last sample");
+        synth.reset();
+        assert_eq!(synth.sample(), [0; 2], "Reset synth should be silent and unpaused");
+        assert_eq!(synth.master, VOLUME_MAX*100, "Reset should keep mix settings");
+    }
+
+    #[test]
+    fn pan() {
+        let mut synth = Synth::new();
+        let voice = &mut synth.voices[0];
+        voice.waveform = Waveform::Square;
+        voice.pan = pan_position(0, 0, 10); // Hard left
+        voice.note_on();
+        let [left, right] = synth.sample();
+        assert!(left > 0 && right == 0, "Hard left should only play left: {} {}", left, right);
+
+        synth.voices[0].pan = pan_position(5, 0, 10); // Center
+        let [left, right] = synth.sample();
+        assert!(left > 0 && left == right, "Center should play equally: {} {}", left, right);
+    }
+
+    // Fraction of a periodic signal's energy outside its harmonics. The signal is exactly `bins`
+    // cycles long in N samples, with N chosen so aliases can't land on a harmonic.
+    fn alias_ratio(signal:&[f64], bins:usize) -> f64 {
+        let n = signal.len();
+        let (mut harmonic, mut total) = (0.0, 0.0);
+        for k in 1..n/2 {
+            let (mut re, mut im) = (0.0, 0.0);
+            for (i, x) in signal.iter().enumerate() {
+                let angle = 2.0 * std::f64::consts::PI * ((k*i) % n) as f64 / n as f64;
+                re += x * angle.cos();
+                im -= x * angle.sin();
+            }
+            let power = re*re + im*im;
+            total += power;
+            if k % bins == 0 { harmonic += power; }
+        }
+        (total - harmonic) / total
+    }
+
+    #[test]
+    fn band_limited() {
+        const N:usize = 2048;
+        const BINS:usize = 151; // About 3.5khz, prime so aliases fall between harmonics
+        let freq = (BINS as u32) << (32 - 11); // BINS cycles per N samples
+
+        for waveform in [Waveform::Saw, Waveform::Square] {
+            let mut voice = Voice::new(waveform);
+            voice.phase = 0x1234567; // Don't line the jumps up with samples
+            let phase = voice.phase;
+            let blep:Vec<f64> = (0..N).map(|_| voice.oscillator(freq) as f64).collect();
+            let naive:Vec<f64> = (0..N as u32).map(|i| {
+                let p = phase.wrapping_add(i.wrapping_mul(freq));
+                match waveform {
+                    Waveform::Saw => (p >> 16) as f64 - 32768.0,
+                    _ => if p < 1<<31 { 32767.0 } else { -32768.0 },
+                }
+            }).collect();
+
+            let (blep, naive) = (alias_ratio(&blep, BINS), alias_ratio(&naive, BINS));
+            assert!(blep < naive / 10.0, "{:?} alias energy should drop: {} vs naive {}", waveform, blep, naive);
+            assert!(blep < 0.01, "{:?} alias energy should be small: {}", waveform, blep);
+        }
+    }
+
+    #[test]
+    fn filters() {
+        let mut filter = ChannelFilter::default();
+        let out:Vec<i32> = (0..4800).map(|_| filter.run(10000, None, true)).collect();
+        assert!(out[0] > 9000 && out[4799].abs() < 100, "DC blocker should remove constant offset: {} {}", out[0], out[4799]);
+
+        // 12khz square (alternating samples) through a 1khz low-pass
+        let mut filter = ChannelFilter::default();
+        let coefficient = Some(lowpass_coefficient(1000));
+        let out:Vec<i32> = (0..480).map(|i| filter.run(if i % 4 < 2 { 10000 } else { -10000 }, coefficient, false)).collect();
+        let peak = out[240..].iter().map(|v| v.abs()).max().unwrap();
+        assert!(peak < 2000, "Low-pass should attenuate high frequencies: {}", peak);
+    }
+}
@@ -0,0 +1,127 @@
+// Frame timing. Measures the real video refresh rate (frame_counter against the CPU cycle counter)
+// so audio and game speed don't depend on the display running at exactly 60hz.
+// Notes:
+//    Game logic runs on a fixed timestep of SIM_HZ steps per second. Each frame, frame() says how
+//    many steps to run: always 1 at 60hz, alternately 1 and 2 at 50hz, and extra steps to catch up
+//    after dropped frames (up to MAX_STEPS). A refresh rate within 1/SNAP of SIM_HZ counts as exactly
+//    SIM_HZ, so a display measured at 59.9 or 60.1hz doesn't get a stray 0 or 2 step frame every
+//    few seconds; the game just runs that little bit slow or fast.
+//    Until the first measurement finishes (MEASURE_FRAMES frames), the display is assumed to be 60hz.
+//    The divisions here happen once per measurement window, not per frame.
+
+use litex_pac as pac;
+use crate::synth::SAMPLE_RATE;
+
+pub const CPU_HZ:u32 = pac::constants::CONFIG_CLOCK_FREQUENCY; // System clock of the core
+pub const SIM_HZ:u32 = 60; // Game logic steps per second
+const STEP_CYCLES:u32 = CPU_HZ / SIM_HZ;
+const SNAP:u32 = 100; // Frames within 1% of a step long are exactly a step
+const MEASURE_FRAMES:u32 = 32; // Frames per measurement window
+const MAX_STEPS:u32 = 4; // Don't try to catch up more than this in one frame
+
+// Cycle counter, for measuring
+pub fn now() -> u64 {
+    riscv::register::mcycle::read64()
+}
+
+pub struct Timing {
+    pub frame_cycles: u32,      // Measured cycles per video frame
+    pub refresh_millihz: u32,   // Measured refresh rate, in thousandths of a hz
+    pub samples_per_frame: u32, // Audio samples played in one video frame (rounded up)
+    last: Option<(u32, u64)>,   // frame_counter and cycle count at last frame()
+    window_frames: u32,
+    window_cycles: u64,
+    step_debt: u32,             // Cycles of game time not yet simulated
+}
+
+impl Timing {
+    pub fn new() -> Self {
+        let mut timing = Timing { frame_cycles:0, refresh_millihz:0, samples_per_frame:0,
+            last:None, window_frames:0, window_cycles:0, step_debt:0 };
+        timing.set_frame_cycles(CPU_HZ / 60);
+        timing
+    }
+
+    fn set_frame_cycles(&mut self, cycles:u32) {
+        let cycles = cycles.max(1);
+        self.frame_cycles = cycles;
+        self.refresh_millihz = (CPU_HZ as u64 * 1000 / cycles as u64) as u32;
+        self.samples_per_frame = (SAMPLE_RATE as u64 * cycles as u64).div_ceil(CPU_HZ as u64) as u32;
+    }
+
+    // Call once per frame, right after vblank, with the video frame counter and now().
+    // Returns how many game logic steps to run this frame.
+    pub fn frame(&mut self, frame_counter:u32, cycles:u64) -> u32 {
+        let Some((last_counter, last_cycles)) = self.last.replace((frame_counter, cycles)) else { return 1 };
+        let frames = frame_counter.wrapping_sub(last_counter);
+
+        // Measure
+        self.window_frames += frames;
+        self.window_cycles += cycles.wrapping_sub(last_cycles);
+        if self.window_frames >= MEASURE_FRAMES {
+            self.set_frame_cycles((self.window_cycles / self.window_frames as u64) as u32);
+            self.window_frames = 0;
+            self.window_cycles = 0;
+        }
+
+        // Use whole measured frames rather than raw cycles, so jitter in when we noticed vblank
+        // doesn't turn into jitter in the step count
+        let frame_cycles = if self.frame_cycles.abs_diff(STEP_CYCLES) <= STEP_CYCLES / SNAP { STEP_CYCLES } else { self.frame_cycles };
+        self.step_debt = self.step_debt.saturating_add(frames.saturating_mul(frame_cycles));
+        let steps = (self.step_debt / STEP_CYCLES).min(MAX_STEPS);
+        self.step_debt = (self.step_debt - steps*STEP_CYCLES).min(STEP_CYCLES); // Drop debt past MAX_STEPS
+        steps
+    }
+}
+
+// Unit tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run frames at a refresh rate in millihz, return the step count of each
+    fn run_millihz(timing:&mut Timing, millihz:u64, frames:u32, counter:&mut u32, cycles:&mut u64) -> alloc::vec::Vec<u32> {
+        (0..frames).map(|_| {
+            *counter += 1;
+            *cycles += CPU_HZ as u64 * 1000 / millihz;
+            timing.frame(*counter, *cycles)
+        }).collect()
+    }
+
+    // Run frames at a refresh rate, return total steps
+    fn run(timing:&mut Timing, hz:u32, frames:u32, counter:&mut u32, cycles:&mut u64) -> u32 {
+        run_millihz(timing, hz as u64 * 1000, frames, counter, cycles).iter().sum()
+    }
+
+    #[test]
+    fn refresh_rates() {
+        let (mut counter, mut cycles) = (0, 0);
+        let mut timing = Timing::new();
+        timing.frame(counter, cycles);
+        assert_eq!(run(&mut timing, 60, 120, &mut counter, &mut cycles), 120, "60hz should run one step per frame");
+        assert_eq!(timing.samples_per_frame, 800);
+
+        let mut timing = Timing::new();
+        run(&mut timing, 50, MEASURE_FRAMES + 1, &mut counter, &mut cycles); // Measure
+        assert_eq!(timing.refresh_millihz / 1000, 50);
+        assert_eq!(timing.samples_per_frame, 960);
+        let steps = run(&mut timing, 50, 100, &mut counter, &mut cycles);
+        assert!((119..=121).contains(&steps), "50hz should still run 60 steps per second: {}", steps);
+
+        // Close to 60hz is treated as 60hz, rather than drifting into 0 and 2 step frames
+        for millihz in [59_900, 60_100] {
+            let mut timing = Timing::new();
+            run(&mut timing, 60, MEASURE_FRAMES + 1, &mut counter, &mut cycles);
+            run_millihz(&mut timing, millihz, MEASURE_FRAMES, &mut counter, &mut cycles); // Measure
+            assert_eq!(timing.refresh_millihz.abs_diff(millihz as u32) / 10, 0, "Should measure {}", millihz);
+            let steps = run_millihz(&mut timing, millihz, 1000, &mut counter, &mut cycles);
+            assert!(steps.iter().all(|&s| s == 1), "{} millihz should run one step every frame", millihz);
+        }
+
+        // Dropped frames are caught up
+        counter += 3;
+        cycles += 3 * (CPU_HZ / 50) as u64;
+        assert!(timing.frame(counter, cycles) >= 3, "Dropped frames should be caught up");
+    }
+}
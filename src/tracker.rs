@@ -0,0 +1,240 @@
+// Host-side converter from tracker text files (music/*.txt) to the compact binary song format
+// played by music.rs. This file is used by build.rs, and is only compiled into the app for its unit tests.
+//
+// Text format. Blank lines and comments (from a '#' at the start of a line or after a space) are ignored.
+//    tempo 120               Beats per minute
+//    rows_per_beat 4         Rows in one beat (default 4)
+//    channels 3              1-4 channels, each plays one note at a time
+//    rows 16                 Rows per pattern
+//    loop 1                  Optional: index into "order" to jump to after the last pattern.
+//                            Without this the song stops at the end.
+//    instrument 0 square duty 25 volume 200 adsr 0 1200 50 2400
+//                            Waveform is saw, square, triangle or noise. duty (square only) and
+//                            sustain are percent, volume is 0-255, attack/decay/release are samples.
+//                            All settings after the waveform are optional.
+//    pattern 0               Followed by exactly "rows" row lines, each with one cell per channel
+//    C-4 0 | --- . | E#3 1   A cell is a note and an instrument number, separated by |
+//                            Notes are letter, - or #, octave (C-4 is middle C). "---" is no note,
+//                            "===" releases the channel's note. Instrument "." means the last
+//                            instrument used on that channel.
+//    order 0 0 1 2           Patterns in play order
+//
+// The binary format is described in songformat.rs.
+
+use std::collections::BTreeMap;
+use crate::songformat::*;
+
+const SAMPLE_RATE:u32 = 48000; // Same as synth.rs
+
+#[derive(Default)]
+struct Instrument {
+    waveform: u8,
+    duty: u8,
+    volume: u8,
+    sustain: u8,
+    attack: u16,
+    decay: u16,
+    release: u16,
+}
+
+fn parse_num<T:std::str::FromStr>(s:Option<&str>, what:&str) -> Result<T, String> {
+    let s = s.ok_or_else(|| format!("missing {what}"))?;
+    s.parse().map_err(|_| format!("bad {what} \"{s}\""))
+}
+
+fn percent_to_u8(percent:u32) -> u8 {
+    (percent.min(100) * 255 / 100) as u8
+}
+
+fn parse_instrument(words:&mut std::str::SplitWhitespace) -> Result<(u8, Instrument), String> {
+    let idx = parse_num(words.next(), "instrument number")?;
+    let waveform = match words.next() {
+        Some("saw") => 0,
+        Some("square") => 1,
+        Some("triangle") => 2,
+        Some("noise") => 3,
+        w => return Err(format!("bad waveform {w:?}")),
+    };
+    let mut instrument = Instrument { waveform, duty:128, volume:255, sustain:255, ..Default::default() };
+    while let Some(key) = words.next() {
+        match key {
+            "duty" => instrument.duty = percent_to_u8(parse_num(words.next(), "duty")?),
+            "volume" => instrument.volume = parse_num(words.next(), "volume")?,
+            "adsr" => {
+                instrument.attack = parse_num(words.next(), "attack")?;
+                instrument.decay = parse_num(words.next(), "decay")?;
+                instrument.sustain = percent_to_u8(parse_num(words.next(), "sustain")?);
+                instrument.release = parse_num(words.next(), "release")?;
+            }
+            _ => return Err(format!("unknown instrument setting \"{key}\"")),
+        }
+    }
+    Ok((idx, instrument))
+}
+
+fn parse_note(s:&str) -> Result<u8, String> {
+    if s == "---" { return Ok(NOTE_NONE) }
+    if s == "===" { return Ok(NOTE_OFF) }
+    let bytes = s.as_bytes();
+    if bytes.len() != 3 { return Err(format!("bad note \"{s}\"")) }
+    let semitone = match bytes[0] {
+        b'C' => 0, b'D' => 2, b'E' => 4, b'F' => 5, b'G' => 7, b'A' => 9, b'B' => 11,
+        _ => return Err(format!("bad note letter in \"{s}\"")),
+    };
+    let sharp = match bytes[1] { b'-' => 0, b'#' => 1, _ => return Err(format!("bad accidental in \"{s}\"")) };
+    let octave = (bytes[2] as char).to_digit(10).ok_or_else(|| format!("bad octave in \"{s}\""))? as i32;
+    let midi = (octave+1)*12 + semitone + sharp;
+    if !(1..=127).contains(&midi) { return Err(format!("note out of range \"{s}\"")) }
+    Ok(midi as u8)
+}
+
+fn parse_cell(s:&str) -> Result<[u8;2], String> {
+    let mut words = s.split_whitespace();
+    let note = parse_note(words.next().ok_or("empty cell")?)?;
+    let instrument = match words.next() {
+        None | Some(".") => INSTRUMENT_KEEP,
+        Some(n) => parse_num(Some(n), "cell instrument")?,
+    };
+    Ok([note, instrument])
+}
+
+// Text song to binary song
+pub fn convert(text:&str) -> Result<Vec<u8>, String> {
+    let mut tempo:u32 = 120;
+    let mut rows_per_beat:u32 = 4;
+    let mut channels:usize = 1;
+    let mut rows:usize = 16;
+    let mut loop_to:u8 = NO_LOOP;
+    let mut instruments:BTreeMap<u8, Instrument> = BTreeMap::new();
+    let mut patterns:BTreeMap<u8, Vec<[u8;2]>> = BTreeMap::new();
+    let mut order:Vec<u8> = Vec::new();
+    let mut current_pattern:Option<u8> = None;
+
+    for (line_idx, line) in text.lines().enumerate() {
+        // A comment's '#' starts the line or follows a space; otherwise it's a sharp, as in C#4
+        let comment = line.char_indices().find(|&(at, c)| c == '#' && (at == 0 || line[..at].ends_with(char::is_whitespace)));
+        let line = comment.map_or(line, |(at, _)| &line[..at]).trim();
+        if line.is_empty() { continue }
+        let fail = |e:String| format!("line {}: {}", line_idx+1, e);
+
+        if let Some(pattern) = current_pattern { // Inside a pattern, lines are rows
+            let cells = patterns.get_mut(&pattern).unwrap();
+            if cells.len() < rows*channels {
+                let row:Vec<&str> = line.split('|').collect();
+                if row.len() != channels { return Err(fail(format!("expected {channels} cells, found {}", row.len()))) }
+                for cell in row { cells.push(parse_cell(cell).map_err(fail)?); }
+                continue;
+            }
+            current_pattern = None;
+        }
+
+        let mut words = line.split_whitespace();
+        match words.next().unwrap() {
+            "tempo" => tempo = parse_num(words.next(), "tempo").map_err(fail)?,
+            "rows_per_beat" => rows_per_beat = parse_num(words.next(), "rows_per_beat").map_err(fail)?,
+            "channels" => channels = parse_num(words.next(), "channels").map_err(fail)?,
+            "rows" => rows = parse_num(words.next(), "rows").map_err(fail)?,
+            "loop" => loop_to = parse_num(words.next(), "loop").map_err(fail)?,
+            "instrument" => {
+                let (idx, instrument) = parse_instrument(&mut words).map_err(fail)?;
+                instruments.insert(idx, instrument);
+            }
+            "pattern" => {
+                let idx = parse_num(words.next(), "pattern number").map_err(fail)?;
+                patterns.insert(idx, Vec::new());
+                current_pattern = Some(idx);
+            }
+            "order" => {
+                for word in words {
+                    order.push(parse_num(Some(word), "order entry").map_err(fail)?);
+                }
+            }
+            word => return Err(fail(format!("unknown command \"{word}\""))),
+        }
+    }
+
+    // Validate
+    if !(1..=MAX_CHANNELS).contains(&channels) { return Err(format!("channels must be 1-{MAX_CHANNELS}")) }
+    if rows == 0 || rows > 255 { return Err("rows must be 1-255".into()) }
+    if tempo == 0 || rows_per_beat == 0 { return Err("tempo and rows_per_beat must be nonzero".into()) }
+    if order.is_empty() || order.len() > 255 { return Err("order must have 1-255 entries".into()) }
+    if loop_to != NO_LOOP && loop_to as usize >= order.len() { return Err("loop is past end of order".into()) }
+    for (idx, cells) in &patterns {
+        if cells.len() != rows*channels { return Err(format!("pattern {idx} has the wrong number of rows")) }
+        for cell in cells {
+            if cell[1] != INSTRUMENT_KEEP && !instruments.contains_key(&cell[1]) {
+                return Err(format!("pattern {idx} uses undefined instrument {}", cell[1]))
+            }
+        }
+    }
+    // Instruments and patterns are stored densely, so numbering must have no gaps
+    if instruments.keys().copied().ne(0..instruments.len() as u8) { return Err("instruments must be numbered 0, 1, 2...".into()) }
+    if patterns.keys().copied().ne(0..patterns.len() as u8) { return Err("patterns must be numbered 0, 1, 2...".into()) }
+    if let Some(bad) = order.iter().find(|&&p| !patterns.contains_key(&p)) { return Err(format!("order uses undefined pattern {bad}")) }
+
+    let samples_per_row = SAMPLE_RATE*60 / (tempo*rows_per_beat);
+    let samples_per_row:u16 = samples_per_row.try_into().map_err(|_| "tempo too slow".to_string())?;
+
+    let mut out = vec![VERSION];
+    out.extend(samples_per_row.to_le_bytes());
+    out.extend([channels as u8, instruments.len() as u8, patterns.len() as u8, rows as u8, order.len() as u8, loop_to]);
+    assert_eq!(out.len(), HEADER_SIZE);
+    for instrument in instruments.values() {
+        out.extend([instrument.waveform, instrument.duty, instrument.volume, instrument.sustain]);
+        out.extend(instrument.attack.to_le_bytes());
+        out.extend(instrument.decay.to_le_bytes());
+        out.extend(instrument.release.to_le_bytes());
+    }
+    assert_eq!(out.len(), HEADER_SIZE + instruments.len()*INSTRUMENT_SIZE);
+    out.extend(&order);
+    for cells in patterns.values() {
+        for cell in cells { out.extend(cell); }
+    }
+    Ok(out)
+}
+
+// Unit tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SONG:&str = "tempo 120\nchannels 2\nrows 2\nloop 0\ninstrument 0 square duty 50\ninstrument 1 noise\n\
+        pattern 0\nC-4 0 | --- .\n=== . | A#3 1\norder 0 0\n";
+
+    // Convert SONG with one line swapped for another (or added, if from is empty), expecting an error
+    fn error(from:&str, to:&str) -> String {
+        let text = if from.is_empty() { alloc::format!("{SONG}{to}\n") } else { SONG.replacen(from, to, 1) };
+        convert(&text).expect_err(to)
+    }
+
+    #[test]
+    fn converts() {
+        let out = convert(SONG).unwrap();
+        assert_eq!(out[0], VERSION);
+        assert_eq!(u16::from_le_bytes([out[1], out[2]]), 6000, "Samples per row at 120bpm, 4 rows per beat");
+        assert_eq!(out[3..HEADER_SIZE], [2, 2, 1, 2, 2, 0], "Channels, instruments, patterns, rows, order length, loop");
+        let order = HEADER_SIZE + 2*INSTRUMENT_SIZE;
+        assert_eq!(out[HEADER_SIZE..HEADER_SIZE+2], [1, 127], "Square at 50% duty");
+        assert_eq!(out[order..order+2], [0, 0], "Order");
+        assert_eq!(out[order+2..], [60, 0, NOTE_NONE, INSTRUMENT_KEEP, NOTE_OFF, INSTRUMENT_KEEP, 58, 1], "Cells");
+    }
+
+    #[test]
+    fn rejects() {
+        assert!(error("tempo 120", "tempi 120").contains("line 1: unknown command"));
+        assert!(error("tempo 120", "tempo fast").contains("bad tempo"));
+        assert!(error("tempo 120", "tempo 1").contains("tempo too slow"));
+        assert!(convert("channels 5\norder 0").unwrap_err().contains("channels must be 1-4"));
+        assert!(error("loop 0", "loop 2").contains("loop is past end"));
+        assert!(error("square duty", "sine duty").contains("bad waveform"));
+        assert!(error("C-4 0 |", "H-4 0 |").contains("bad note letter"));
+        assert!(error("C-4 0 |", "C-4 0 | --- . |").contains("line 8: expected 2 cells"));
+        assert!(error("A#3 1", "A#3 2").contains("undefined instrument 2"));
+        let gap = SONG.replace("instrument 1 noise", "instrument 2 noise").replace("A#3 1", "A#3 2");
+        assert!(convert(&gap).unwrap_err().contains("instruments must be numbered"));
+        assert!(error("order 0 0", "order 0 1").contains("undefined pattern 1"));
+        assert!(error("order 0 0", "").contains("order must have"));
+        assert!(error("", "pattern 1\nC-4 0 | --- .").contains("pattern 1 has the wrong number of rows"));
+    }
+}